@@ -1,7 +1,20 @@
+//! ACPI table discovery and parsing.
+
+pub mod aml;
+
 use super::mem::PhysToVirt;
-use acpi::{AcpiHandler, PhysicalMapping};
+use acpi::{
+    address::{AddressSpace, GenericAddress},
+    fadt::Fadt,
+    hpet::HpetTable,
+    madt::Madt,
+    mcfg::Mcfg,
+    AcpiHandler, AcpiTables, AmlTable, HpetInfo, InterruptModel, PhysicalMapping, PlatformInfo,
+};
+use alloc::alloc::Global;
 use core::ptr::NonNull;
-use x86_64::{PhysAddr, VirtAddr};
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr};
 
 #[derive(Clone, Copy)]
 pub struct SystemAcpiHandler;
@@ -12,8 +25,217 @@ impl AcpiHandler for SystemAcpiHandler {
         physical_address: usize,
         size: usize,
     ) -> PhysicalMapping<Self, T> {
-        todo!();
+        let vaddr = PhysAddr::new(physical_address as u64).to_virt();
+
+        // SAFETY: physical memory is mapped by the HHDM, so the region is already accessible at
+        // `vaddr`.
+        unsafe {
+            PhysicalMapping::new(
+                physical_address,
+                NonNull::new(vaddr.as_mut_ptr()).expect("ACPI region mapped at null"),
+                size,
+                size,
+                *self,
+            )
+        }
+    }
+
+    fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
+        // Regions live in the HHDM, which is never unmapped.
+    }
+}
+
+/// The physical address of the RSDP.
+static RSDP: Once<PhysAddr> = Once::new();
+
+static PLATFORM_INFO: Once<PlatformInfo<'static, Global>> = Once::new();
+
+/// Return the ACPI tables of the system.
+///
+/// Returns `None` if ACPI has not been initialized or the tables are invalid.
+pub fn tables() -> Option<AcpiTables<SystemAcpiHandler>> {
+    let rsdp = RSDP.get()?;
+
+    // SAFETY: the RSDP address was provided by the bootloader.
+    unsafe { AcpiTables::from_rsdp(SystemAcpiHandler, rsdp.as_u64() as usize) }.ok()
+}
+
+/// Return the platform information gathered from the ACPI tables, such as the interrupt model
+/// and the processors present.
+pub fn platform_info() -> Option<&'static PlatformInfo<'static, Global>> {
+    PLATFORM_INFO.get()
+}
+
+/// Return the description of the HPET, if the system has one.
+pub fn hpet_info() -> Option<HpetInfo> {
+    HpetInfo::new(&tables()?).ok()
+}
+
+/// Return the CMOS register holding the century, if the FADT describes one.
+pub fn rtc_century_register() -> Option<u8> {
+    let century = tables()?.find_table::<Fadt>().ok()?.century;
+    (century != 0).then_some(century)
+}
+
+/// Return the AML stream of a DSDT or SSDT.
+pub(crate) fn aml_stream(table: &AmlTable) -> &'static [u8] {
+    let vaddr = PhysAddr::new(table.address as u64).to_virt();
+
+    // SAFETY: the table is mapped by the HHDM and never modified.
+    unsafe { core::slice::from_raw_parts(vaddr.as_ptr::<u8>(), table.length as usize) }
+}
+
+/// Read a register described by a generic address structure.
+///
+/// Returns `None` if the register lives in an address space that is not supported.
+pub fn read_register(gas: &GenericAddress) -> Option<u64> {
+    let value = match (gas.address_space, gas.bit_width) {
+        // SAFETY: the firmware describes this port as an ACPI register.
+        (AddressSpace::SystemIo, 8) => unsafe { Port::<u8>::new(gas.address as u16).read() as u64 },
+        (AddressSpace::SystemIo, 16) => unsafe {
+            Port::<u16>::new(gas.address as u16).read() as u64
+        },
+        (AddressSpace::SystemIo, 32) => unsafe {
+            Port::<u32>::new(gas.address as u16).read() as u64
+        },
+        (AddressSpace::SystemMemory, width) => {
+            let vaddr = PhysAddr::new(gas.address).to_virt();
+
+            // SAFETY: the firmware describes this memory as an ACPI register, which is mapped by
+            // the HHDM.
+            unsafe {
+                match width {
+                    8 => core::ptr::read_volatile(vaddr.as_ptr::<u8>()) as u64,
+                    16 => core::ptr::read_volatile(vaddr.as_ptr::<u16>()) as u64,
+                    32 => core::ptr::read_volatile(vaddr.as_ptr::<u32>()) as u64,
+                    64 => core::ptr::read_volatile(vaddr.as_ptr::<u64>()),
+                    _ => return None,
+                }
+            }
+        }
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// Write to a register described by a generic address structure.
+///
+/// Returns `None` if the register lives in an address space that is not supported.
+pub fn write_register(gas: &GenericAddress, value: u64) -> Option<()> {
+    match (gas.address_space, gas.bit_width) {
+        // SAFETY: the firmware describes this port as an ACPI register.
+        (AddressSpace::SystemIo, 8) => unsafe {
+            Port::<u8>::new(gas.address as u16).write(value as u8)
+        },
+        (AddressSpace::SystemIo, 16) => unsafe {
+            Port::<u16>::new(gas.address as u16).write(value as u16)
+        },
+        (AddressSpace::SystemIo, 32) => unsafe {
+            Port::<u32>::new(gas.address as u16).write(value as u32)
+        },
+        (AddressSpace::SystemMemory, width) => {
+            let vaddr = PhysAddr::new(gas.address).to_virt();
+
+            // SAFETY: the firmware describes this memory as an ACPI register, which is mapped by
+            // the HHDM.
+            unsafe {
+                match width {
+                    8 => core::ptr::write_volatile(vaddr.as_mut_ptr::<u8>(), value as u8),
+                    16 => core::ptr::write_volatile(vaddr.as_mut_ptr::<u16>(), value as u16),
+                    32 => core::ptr::write_volatile(vaddr.as_mut_ptr::<u32>(), value as u32),
+                    64 => core::ptr::write_volatile(vaddr.as_mut_ptr::<u64>(), value),
+                    _ => return None,
+                }
+            }
+        }
+        _ => return None,
+    }
+
+    Some(())
+}
+
+/// Log a summary of the tables, processors and interrupt routing described by ACPI.
+fn log_summary(tables: &AcpiTables<SystemAcpiHandler>, platform: &PlatformInfo<'_, Global>) {
+    macro log_table($tables:expr, $table:ty, $name:literal) {
+        match $tables.find_table::<$table>() {
+            Ok(table) => log::info!("{} found at {:#X}", $name, table.physical_start()),
+            Err(_) => log::info!("{} not present", $name),
+        }
+    }
+
+    log::info!("ACPI revision {}", tables.revision());
+
+    log_table!(tables, Fadt, "FADT");
+    log_table!(tables, Madt, "MADT");
+    log_table!(tables, HpetTable, "HPET");
+    log_table!(tables, Mcfg, "MCFG");
+
+    if let Ok(dsdt) = tables.dsdt() {
+        log::info!(
+            "DSDT found at {:#X} ({} bytes of AML)",
+            dsdt.address,
+            dsdt.length
+        );
+    }
+
+    log::info!("{} SSDT(s) found", tables.ssdts().count());
+
+    if let Some(processors) = &platform.processor_info {
+        log::info!(
+            "boot processor: UID {}, local APIC ID {}",
+            processors.boot_processor.processor_uid,
+            processors.boot_processor.local_apic_id
+        );
+
+        for ap in processors.application_processors.iter() {
+            log::info!(
+                "application processor: UID {}, local APIC ID {} ({:?})",
+                ap.processor_uid,
+                ap.local_apic_id,
+                ap.state
+            );
+        }
+    }
+
+    if let InterruptModel::Apic(apic) = &platform.interrupt_model {
+        log::info!(
+            "interrupt model: APIC (local APIC at {:#X}, {} I/O APIC(s), legacy PICs: {})",
+            apic.local_apic_address,
+            apic.io_apics.len(),
+            apic.also_has_legacy_pics
+        );
+
+        for iso in apic.interrupt_source_overrides.iter() {
+            log::info!(
+                "interrupt override: ISA IRQ {} -> GSI {} ({:?}, {:?})",
+                iso.isa_source,
+                iso.global_system_interrupt,
+                iso.polarity,
+                iso.trigger_mode
+            );
+        }
+    } else {
+        log::info!("interrupt model: unknown");
+    }
+}
+
+/// Initialize ACPI using the RSDP located at the physical address `rsdp`.
+///
+/// If the tables are invalid or the platform information cannot be parsed, it is left unset so
+/// that [`platform_info`] returns `None` and the interrupt controllers fall back to the 8259 PIC.
+pub fn init(rsdp: PhysAddr) {
+    RSDP.call_once(|| rsdp);
+
+    let Some(tables) = tables() else {
+        log::warn!("acpi: invalid RSDP or root table, continuing without ACPI");
+        return;
+    };
+
+    match PlatformInfo::new(&tables) {
+        Ok(platform) => log_summary(&tables, PLATFORM_INFO.call_once(|| platform)),
+        Err(err) => log::warn!("acpi: unable to parse platform info: {err:?}"),
     }
 
-    fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {}
+    aml::init(&tables);
 }
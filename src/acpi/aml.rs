@@ -0,0 +1,336 @@
+//! AML interpreter integration, giving access to the ACPI namespace described by the DSDT and
+//! SSDTs.
+
+use super::{aml_stream, SystemAcpiHandler};
+use crate::{drivers::pci::PciAddress, mem::PhysToVirt};
+use ::acpi::AcpiTables;
+use ::aml::{
+    pci_routing::PciRoutingTable, AmlContext, AmlError, AmlName, AmlValue, DebugVerbosity, Handler,
+    LevelType, NamespaceLevel,
+};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use spin::{Mutex, Once};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+pub use ::aml::value::Args;
+
+/// Services the memory, I/O port and PCI configuration space accesses made by AML.
+struct KernelAmlHandler;
+
+impl KernelAmlHandler {
+    fn read<T: Copy>(address: usize) -> T {
+        let vaddr = PhysAddr::new(address as u64).to_virt();
+
+        // SAFETY: AML only accesses memory described by its operation regions, which is mapped by
+        // the HHDM.
+        unsafe { core::ptr::read_volatile(vaddr.as_ptr::<T>()) }
+    }
+
+    fn write<T: Copy>(address: usize, value: T) {
+        let vaddr = PhysAddr::new(address as u64).to_virt();
+
+        // SAFETY: AML only accesses memory described by its operation regions, which is mapped by
+        // the HHDM.
+        unsafe { core::ptr::write_volatile(vaddr.as_mut_ptr::<T>(), value) }
+    }
+
+    /// Return the address of a PCI function, or `None` if it lives in a segment other than 0,
+    /// which the legacy configuration mechanism cannot reach.
+    fn pci(segment: u16, bus: u8, device: u8, function: u8) -> Option<PciAddress> {
+        if segment != 0 {
+            log::warn!("aml: PCI segment {segment} is not accessible");
+            return None;
+        }
+
+        Some(PciAddress::new(bus, device, function))
+    }
+}
+
+impl Handler for KernelAmlHandler {
+    fn read_u8(&self, address: usize) -> u8 {
+        Self::read(address)
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        Self::read(address)
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        Self::read(address)
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        Self::read(address)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        Self::write(address, value)
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        Self::write(address, value)
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        Self::write(address, value)
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        Self::write(address, value)
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        // SAFETY: AML only accesses ports described by its operation regions.
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        // SAFETY: AML only accesses ports described by its operation regions.
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        // SAFETY: AML only accesses ports described by its operation regions.
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        // SAFETY: AML only accesses ports described by its operation regions.
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        // SAFETY: AML only accesses ports described by its operation regions.
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        // SAFETY: AML only accesses ports described by its operation regions.
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        Self::pci(segment, bus, device, function).map_or(u8::MAX, |pci| pci.read_u8(offset))
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        Self::pci(segment, bus, device, function).map_or(u16::MAX, |pci| pci.read_u16(offset))
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        Self::pci(segment, bus, device, function).map_or(u32::MAX, |pci| pci.read_u32(offset))
+    }
+
+    fn write_pci_u8(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
+    ) {
+        if let Some(pci) = Self::pci(segment, bus, device, function) {
+            pci.write_u8(offset, value);
+        }
+    }
+
+    fn write_pci_u16(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
+    ) {
+        if let Some(pci) = Self::pci(segment, bus, device, function) {
+            pci.write_u16(offset, value);
+        }
+    }
+
+    fn write_pci_u32(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        if let Some(pci) = Self::pci(segment, bus, device, function) {
+            pci.write_u32(offset, value);
+        }
+    }
+
+    fn handle_fatal_error(&self, fatal_type: u8, fatal_code: u32, fatal_arg: u64) {
+        log::error!(
+            "aml: firmware reported a fatal error (type {fatal_type:#X}, code {fatal_code:#X}, argument {fatal_arg:#X})"
+        );
+    }
+}
+
+static CONTEXT: Once<Mutex<AmlContext>> = Once::new();
+
+/// Run `f` with exclusive access to the AML interpreter.
+///
+/// Returns `None` if no AML has been loaded.
+pub fn with_context<R>(f: impl FnOnce(&mut AmlContext) -> R) -> Option<R> {
+    Some(f(&mut CONTEXT.get()?.lock()))
+}
+
+/// Parse an absolute path such as `\_SB.PCI0`.
+///
+/// # Panics
+///
+/// This function will panic if `path` is not a valid AML name.
+fn name(path: &str) -> AmlName {
+    AmlName::from_str(path).unwrap_or_else(|_| panic!("aml: invalid path {path}"))
+}
+
+/// Evaluate the object at `path` in `context`, logging any error other than the object not
+/// existing.
+fn evaluate_in(context: &mut AmlContext, path: &AmlName, args: Args) -> Option<AmlValue> {
+    match context.invoke_method(path, args) {
+        Ok(value) => Some(value),
+        Err(AmlError::ValueDoesNotExist(_)) => None,
+        Err(err) => {
+            log::warn!("aml: failed to evaluate {path}: {err:?}");
+            None
+        }
+    }
+}
+
+/// Evaluate the object at the absolute path `path`, invoking it with `args` if it is a method.
+///
+/// Returns `None` if the object does not exist or could not be evaluated.
+pub fn evaluate(path: &str, args: Args) -> Option<AmlValue> {
+    with_context(|context| evaluate_in(context, &name(path), args)).flatten()
+}
+
+/// Walk the namespace, calling `f` with the path of each level. The children of a level are only
+/// visited if `f` returns `true`.
+pub fn walk(mut f: impl FnMut(&AmlName, &NamespaceLevel) -> bool) {
+    with_context(|context| {
+        // The closure never fails, so neither does the traversal.
+        let _ = context.namespace.traverse(|name, level| Ok(f(name, level)));
+    });
+}
+
+/// Decode a compressed EISA ID, such as the integer form of a `_HID`, into its string form.
+fn decode_eisa_id(id: u64) -> String {
+    // The ID is stored big-endian.
+    let id = (id as u32).swap_bytes();
+    let vendor = |shift: u32| (((id >> shift) & 0x1F) as u8 + b'@') as char;
+
+    format!(
+        "{}{}{}{:04X}",
+        vendor(26),
+        vendor(21),
+        vendor(16),
+        id & 0xFFFF
+    )
+}
+
+/// Return the hardware ID (`_HID`) of the device at `device`.
+fn hardware_id(context: &mut AmlContext, device: &AmlName) -> Option<String> {
+    let path = name("_HID").resolve(device).ok()?;
+
+    match evaluate_in(context, &path, Args::EMPTY)? {
+        AmlValue::Integer(id) => Some(decode_eisa_id(id)),
+        AmlValue::String(id) => Some(id),
+        _ => None,
+    }
+}
+
+/// Return the path and hardware ID (`_HID`) of every device in the namespace that has one.
+pub fn devices() -> Vec<(AmlName, String)> {
+    with_context(|context| {
+        let mut paths = Vec::new();
+
+        let _ = context.namespace.traverse(|name, level| {
+            if level.typ == LevelType::Device {
+                paths.push(name.clone());
+            }
+
+            Ok(true)
+        });
+
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let hid = hardware_id(context, &path)?;
+                Some((path, hid))
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Return the paths of the devices with the hardware ID (`_HID`) `hid`, such as `PNP0A03` for
+/// PCI root bridges.
+pub fn find_devices(hid: &str) -> Vec<AmlName> {
+    devices()
+        .into_iter()
+        .filter(|(_, id)| id == hid)
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// Return the PCI interrupt routing table (`_PRT`) of the PCI bridge at `bridge`.
+pub fn pci_routing_table(bridge: &AmlName) -> Option<PciRoutingTable> {
+    with_context(|context| {
+        let path = name("_PRT").resolve(bridge).ok()?;
+
+        match PciRoutingTable::from_prt_path(&path, context) {
+            Ok(table) => Some(table),
+            Err(AmlError::ValueDoesNotExist(_)) => None,
+            Err(err) => {
+                log::warn!("aml: failed to parse {path}: {err:?}");
+                None
+            }
+        }
+    })
+    .flatten()
+}
+
+/// Tell the firmware which interrupt model is in use through `\_PIC`, which changes the routing
+/// returned by `_PRT`.
+pub fn set_interrupt_model(apic: bool) {
+    let args = Args::from_list(vec![AmlValue::Integer(apic as u64)]).unwrap();
+
+    evaluate("\\_PIC", args);
+}
+
+/// Load the DSDT and SSDTs into the AML interpreter and run the initialization methods of the
+/// devices present.
+pub(super) fn init(tables: &AcpiTables<SystemAcpiHandler>) {
+    let Ok(dsdt) = tables.dsdt() else {
+        log::warn!("aml: no DSDT found");
+        return;
+    };
+
+    let mut context = AmlContext::new(Box::new(KernelAmlHandler), DebugVerbosity::None);
+
+    if let Err(err) = context.parse_table(aml_stream(&dsdt)) {
+        log::error!("aml: failed to parse the DSDT: {err:?}");
+        return;
+    }
+
+    for (i, ssdt) in tables.ssdts().enumerate() {
+        if let Err(err) = context.parse_table(aml_stream(&ssdt)) {
+            log::warn!("aml: failed to parse SSDT {i}: {err:?}");
+        }
+    }
+
+    if let Err(err) = context.initialize_objects() {
+        log::warn!("aml: failed to initialize devices: {err:?}");
+    }
+
+    CONTEXT.call_once(|| Mutex::new(context));
+
+    log::info!(
+        "loaded AML namespace with {} identified device(s)",
+        devices().len()
+    );
+}
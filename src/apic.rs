@@ -0,0 +1,219 @@
+//! Advanced Programmable Interrupt Controller (APIC) support.
+
+pub mod ioapic;
+pub mod lapic;
+
+use crate::{acpi::aml, idt, irq};
+use acpi::platform::{
+    interrupt::{InterruptModel, InterruptSourceOverride, Polarity, TriggerMode},
+    PlatformInfo,
+};
+use alloc::{alloc::Global, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use ioapic::IoApic;
+use spin::Once;
+
+/// The number of legacy ISA interrupts.
+const ISA_IRQS: u8 = 16;
+
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+
+/// The global system interrupt each ISA IRQ is connected to, if any.
+static ISA_GSIS: Once<[Option<u32>; ISA_IRQS as usize]> = Once::new();
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Check if interrupts are delivered through the APICs rather than the 8259 PIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Return the I/O APIC handling the global system interrupt `gsi`.
+fn ioapic_for(gsi: u32) -> Option<&'static IoApic> {
+    IO_APICS.get()?.iter().find(|ioapic| ioapic.handles(gsi))
+}
+
+/// Mask or unmask an ISA IRQ at the I/O APIC it is routed through.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let Some(gsi) = ISA_GSIS.get().and_then(|gsis| *gsis.get(irq as usize)?) else {
+        return;
+    };
+
+    if let Some(ioapic) = ioapic_for(gsi) {
+        ioapic.set_masked(gsi, masked);
+    }
+}
+
+/// How an ISA IRQ is connected to the I/O APICs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IsaRoute {
+    gsi: u32,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+}
+
+/// Return the route of every ISA IRQ given the interrupt source overrides of the MADT.
+///
+/// IRQs without an override are connected to the GSI of the same number, unless another IRQ is
+/// overridden to it, as IRQ 0 usually is to GSI 2. Those IRQs have no route.
+fn isa_routes(overrides: &[InterruptSourceOverride]) -> [Option<IsaRoute>; ISA_IRQS as usize] {
+    let mut routes = [None; ISA_IRQS as usize];
+
+    for (irq, route) in (0..ISA_IRQS).zip(&mut routes) {
+        let iso = overrides.iter().find(|iso| iso.isa_source == irq);
+        let claimed = overrides
+            .iter()
+            .any(|iso| iso.global_system_interrupt == irq as u32);
+
+        let (gsi, polarity, trigger_mode) = match iso {
+            Some(iso) => (iso.global_system_interrupt, iso.polarity, iso.trigger_mode),
+            None if claimed => continue,
+            None => (irq as u32, Polarity::SameAsBus, TriggerMode::SameAsBus),
+        };
+
+        // ISA interrupts are active high and edge triggered unless overridden.
+        *route = Some(IsaRoute {
+            gsi,
+            polarity: match polarity {
+                Polarity::SameAsBus => Polarity::ActiveHigh,
+                p => p,
+            },
+            trigger_mode: match trigger_mode {
+                TriggerMode::SameAsBus => TriggerMode::Edge,
+                t => t,
+            },
+        });
+    }
+
+    routes
+}
+
+/// Enable the Local APIC of an application processor, whose ACPI processor UID is
+/// `processor_uid`. Does nothing if the BSP did not switch to the APICs.
+pub fn init_ap(processor_uid: u32) {
+    if !is_enabled() {
+        return;
+    }
+
+    let nmi_lines = match crate::acpi::platform_info().map(|platform| &platform.interrupt_model) {
+        Some(InterruptModel::Apic(apic)) => &apic.local_apic_nmi_lines[..],
+        _ => &[],
+    };
+
+    let lapic = lapic::init_ap();
+    lapic.enable(Some(processor_uid), nmi_lines);
+}
+
+/// Initialize the Local APIC and I/O APICs described by the MADT and mask the 8259 PIC.
+///
+/// If the platform has no MADT, the 8259 PIC set up by [`idt::init`] keeps handling interrupts.
+pub fn init(platform: Option<&PlatformInfo<'_, Global>>) {
+    let Some((apic, platform)) = platform.and_then(|platform| match &platform.interrupt_model {
+        InterruptModel::Apic(apic) => Some((apic, platform)),
+        _ => None,
+    }) else {
+        log::warn!("no MADT found, falling back to the 8259 PIC");
+        return;
+    };
+
+    let processor_uid = platform
+        .processor_info
+        .as_ref()
+        .map(|info| info.boot_processor.processor_uid);
+
+    let lapic = lapic::init(apic.local_apic_address);
+    lapic.enable(processor_uid, &apic.local_apic_nmi_lines);
+    log::info!(
+        "enabled local APIC {id} in {mode} mode",
+        id = lapic.id(),
+        mode = if lapic.is_x2apic() { "x2APIC" } else { "xAPIC" }
+    );
+
+    let ioapics = IO_APICS.call_once(|| {
+        apic.io_apics
+            .iter()
+            .map(|ioapic| {
+                IoApic::new(
+                    ioapic.id,
+                    ioapic.address,
+                    ioapic.global_system_interrupt_base,
+                )
+            })
+            .collect()
+    });
+
+    for ioapic in ioapics {
+        ioapic.mask_all();
+    }
+
+    let routes = isa_routes(&apic.interrupt_source_overrides);
+
+    for (irq, route) in (0..ISA_IRQS).zip(&routes) {
+        let Some(route) = route else {
+            log::debug!("ISA IRQ {irq} is not connected, its GSI is overridden");
+            continue;
+        };
+
+        match ioapic_for(route.gsi) {
+            Some(ioapic) => ioapic.route(
+                route.gsi,
+                idt::PIC1_OFFSET + irq,
+                lapic.id(),
+                route.polarity,
+                route.trigger_mode,
+            ),
+            None => log::warn!("no I/O APIC handles GSI {} (ISA IRQ {irq})", route.gsi),
+        }
+    }
+
+    ISA_GSIS.call_once(|| routes.map(|route| route.map(|route| route.gsi)));
+
+    idt::disable_pic();
+    ENABLED.store(true, Ordering::Release);
+
+    // `_PRT` reports GSIs rather than PIC IRQs once the firmware knows the APICs are in use.
+    aml::set_interrupt_model(true);
+
+    irq::register(
+        irq::line(lapic::ERROR_VECTOR),
+        lapic::error_interrupt,
+        "lapic-error",
+    )
+    .expect("unable to register the local APIC error handler");
+
+    // Lines claimed while the 8259 PIC was in use stay unmasked.
+    for line in 0..ISA_IRQS {
+        if irq::has_handlers(line) {
+            set_irq_masked(line, false);
+        }
+    }
+
+    log::info!(
+        "initialized {n} I/O APIC(s) with {o} interrupt source override(s)",
+        n = ioapics.len(),
+        o = apic.interrupt_source_overrides.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_irqs_whose_gsi_is_overridden() {
+        let overrides = [InterruptSourceOverride {
+            isa_source: 0,
+            global_system_interrupt: 2,
+            polarity: Polarity::SameAsBus,
+            trigger_mode: TriggerMode::SameAsBus,
+        }];
+
+        let gsis = isa_routes(&overrides).map(|route| route.map(|route| route.gsi));
+
+        assert_eq!(gsis[0], Some(2));
+        assert_eq!(gsis[1], Some(1));
+        assert_eq!(gsis[2], None);
+        assert_eq!(gsis[8], Some(8));
+        assert_eq!(gsis.iter().flatten().filter(|&&gsi| gsi == 2).count(), 1);
+    }
+}
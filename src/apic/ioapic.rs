@@ -0,0 +1,151 @@
+//! I/O APIC driver.
+
+use crate::{mem::PhysToVirt, sync::IrqSpinLock};
+use acpi::platform::interrupt::{Polarity, TriggerMode};
+use x86_64::{PhysAddr, VirtAddr};
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_POLARITY_LOW: u64 = 1 << 13;
+const REDIRECTION_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The register window of an I/O APIC: a register is selected by writing its index to
+/// `IOREGSEL`, then accessed through `IOWIN`.
+#[derive(Debug)]
+struct Registers {
+    /// The virtual address of the register space.
+    base: VirtAddr,
+}
+
+impl Registers {
+    /// Read an I/O APIC register.
+    fn read(&mut self, reg: u32) -> u32 {
+        // SAFETY: `base` maps the register space of this I/O APIC.
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            core::ptr::read_volatile((self.base + IOWIN).as_ptr::<u32>())
+        }
+    }
+
+    /// Write to an I/O APIC register.
+    fn write(&mut self, reg: u32, value: u32) {
+        // SAFETY: `base` maps the register space of this I/O APIC.
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            core::ptr::write_volatile((self.base + IOWIN).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn read_redirection(&mut self, entry: u32) -> u64 {
+        let low = self.read(IOREDTBL + entry * 2) as u64;
+        let high = self.read(IOREDTBL + entry * 2 + 1) as u64;
+
+        (high << 32) | low
+    }
+
+    fn write_redirection(&mut self, entry: u32, value: u64) {
+        // Mask the entry while it is being updated.
+        self.write(IOREDTBL + entry * 2, REDIRECTION_MASKED as u32);
+        self.write(IOREDTBL + entry * 2 + 1, (value >> 32) as u32);
+        self.write(IOREDTBL + entry * 2, value as u32);
+    }
+}
+
+#[derive(Debug)]
+pub struct IoApic {
+    /// The register window, locked as selecting a register and accessing it must not be
+    /// interleaved with another CPU doing the same.
+    registers: IrqSpinLock<Registers>,
+
+    /// The ID of this I/O APIC.
+    id: u8,
+
+    /// The first global system interrupt handled by this I/O APIC.
+    gsi_base: u32,
+
+    /// The amount of redirection entries, and thus interrupts, this I/O APIC handles.
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    pub(super) fn new(id: u8, address: u32, gsi_base: u32) -> Self {
+        let mut registers = Registers {
+            base: PhysAddr::new(address as u64).to_virt(),
+        };
+
+        let redirection_entries = ((registers.read(IOAPICVER) >> 16) & 0xFF) + 1;
+
+        Self {
+            registers: IrqSpinLock::new(registers),
+            id,
+            gsi_base,
+            redirection_entries,
+        }
+    }
+
+    /// Return the ID of this I/O APIC.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Check if this I/O APIC handles the global system interrupt `gsi`.
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    /// Mask every interrupt handled by this I/O APIC.
+    pub(super) fn mask_all(&self) {
+        let mut registers = self.registers.lock();
+
+        for entry in 0..self.redirection_entries {
+            registers.write_redirection(entry, REDIRECTION_MASKED);
+        }
+    }
+
+    /// Route the global system interrupt `gsi` to `vector` on the Local APIC with ID `dest`.
+    /// The interrupt is left masked.
+    pub(super) fn route(
+        &self,
+        gsi: u32,
+        vector: u8,
+        dest: u32,
+        polarity: Polarity,
+        trigger_mode: TriggerMode,
+    ) {
+        let mut value = vector as u64 | REDIRECTION_MASKED | ((dest as u64 & 0xFF) << 56);
+
+        if polarity == Polarity::ActiveLow {
+            value |= REDIRECTION_POLARITY_LOW;
+        }
+
+        if trigger_mode == TriggerMode::Level {
+            value |= REDIRECTION_TRIGGER_LEVEL;
+        }
+
+        self.registers
+            .lock()
+            .write_redirection(gsi - self.gsi_base, value);
+    }
+
+    /// Mask or unmask the global system interrupt `gsi`.
+    pub(super) fn set_masked(&self, gsi: u32, masked: bool) {
+        let entry = gsi - self.gsi_base;
+
+        // The entry is read and written back under the lock, so that a concurrent update of it
+        // is not lost.
+        let mut registers = self.registers.lock();
+        let value = registers.read_redirection(entry);
+
+        if masked {
+            registers.write_redirection(entry, value | REDIRECTION_MASKED);
+        } else {
+            registers.write_redirection(entry, value & !REDIRECTION_MASKED);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Local APIC driver, supporting both the memory-mapped xAPIC and the MSR-based x2APIC modes.
+
+use crate::{
+    cpu::{self, Feature},
+    idt::TrapFrame,
+    irq::IrqReturn,
+    mem::PhysToVirt,
+};
+use acpi::platform::interrupt::{LocalInterruptLine, NmiLine, NmiProcessor};
+use spin::Once;
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0xF_FFFF_F000;
+
+/// The base MSR of the x2APIC register space.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Offsets of the Local APIC registers in the xAPIC register space.
+pub(super) mod reg {
+    pub const ID: u32 = 0x20;
+    pub const VERSION: u32 = 0x30;
+    pub const TPR: u32 = 0x80;
+    pub const EOI: u32 = 0xB0;
+    pub const SVR: u32 = 0xF0;
+    pub const ESR: u32 = 0x280;
+    pub const ICR_LOW: u32 = 0x300;
+    pub const ICR_HIGH: u32 = 0x310;
+    pub const LVT_TIMER: u32 = 0x320;
+    pub const LVT_PERF: u32 = 0x340;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_LINT1: u32 = 0x360;
+    pub const LVT_ERROR: u32 = 0x370;
+    pub const TIMER_INITIAL_COUNT: u32 = 0x380;
+    pub const TIMER_CURRENT_COUNT: u32 = 0x390;
+    pub const TIMER_DIVIDE: u32 = 0x3E0;
+}
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+const ICR_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Divide the bus clock by 16 to obtain the timer clock.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// The vector the Local APIC delivers spurious interrupts on.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The vector the Local APIC delivers internal errors on.
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+/// The vector the Local APIC timer fires on.
+pub const TIMER_VECTOR: u8 = 0xFD;
+
+/// The operating modes of the Local APIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Count down from the initial count once.
+    OneShot = 0b00 << 17,
+
+    /// Count down from the initial count repeatedly.
+    Periodic = 0b01 << 17,
+
+    /// Fire when the TSC reaches the value written to `IA32_TSC_DEADLINE`.
+    TscDeadline = 0b10 << 17,
+}
+
+/// How the Local APIC registers are accessed.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// Registers are memory-mapped at the given address.
+    XApic(VirtAddr),
+
+    /// Registers are accessed through MSRs.
+    X2Apic,
+}
+
+#[derive(Debug)]
+pub struct LocalApic {
+    mode: Mode,
+}
+
+impl LocalApic {
+    /// Read a Local APIC register.
+    pub(super) fn read(&self, reg: u32) -> u32 {
+        match self.mode {
+            // SAFETY: `base` maps the Local APIC register space and `reg` is a valid offset.
+            Mode::XApic(base) => unsafe {
+                core::ptr::read_volatile((base + reg as u64).as_ptr::<u32>())
+            },
+            // SAFETY: x2APIC mode is enabled, so the Local APIC MSRs are accessible.
+            Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32 },
+        }
+    }
+
+    /// Write to a Local APIC register.
+    pub(super) fn write(&self, reg: u32, value: u32) {
+        match self.mode {
+            // SAFETY: `base` maps the Local APIC register space and `reg` is a valid offset.
+            Mode::XApic(base) => unsafe {
+                core::ptr::write_volatile((base + reg as u64).as_mut_ptr::<u32>(), value)
+            },
+            // SAFETY: x2APIC mode is enabled, so the Local APIC MSRs are accessible.
+            Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(value as u64) },
+        }
+    }
+
+    /// Check if the Local APIC is running in x2APIC mode.
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self.mode, Mode::X2Apic)
+    }
+
+    /// Return the ID of the Local APIC of the current CPU.
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            Mode::XApic(_) => self.read(reg::ID) >> 24,
+            Mode::X2Apic => self.read(reg::ID),
+        }
+    }
+
+    /// Signal the end of an interrupt.
+    pub fn eoi(&self) {
+        self.write(reg::EOI, 0);
+    }
+
+    /// Enable the Local APIC of the current CPU, routing the LINT pins to NMI as described by
+    /// the MADT.
+    pub fn enable(&self, processor_uid: Option<u32>, nmi_lines: &[NmiLine]) {
+        // Accept all interrupts.
+        self.write(reg::TPR, 0);
+
+        self.write(reg::LVT_TIMER, LVT_MASKED);
+        self.write(reg::LVT_PERF, LVT_MASKED);
+        self.write(reg::LVT_LINT0, LVT_MASKED);
+        self.write(reg::LVT_LINT1, LVT_MASKED);
+        self.write(reg::LVT_ERROR, ERROR_VECTOR as u32);
+
+        for nmi in nmi_lines {
+            let applies = match nmi.processor {
+                NmiProcessor::All => true,
+                NmiProcessor::ProcessorUid(uid) => Some(uid) == processor_uid,
+            };
+
+            if applies {
+                let lvt = match nmi.line {
+                    LocalInterruptLine::Lint0 => reg::LVT_LINT0,
+                    LocalInterruptLine::Lint1 => reg::LVT_LINT1,
+                };
+
+                self.write(lvt, LVT_DELIVERY_MODE_NMI);
+            }
+        }
+
+        // The error status register must be written to before it is read.
+        self.write(reg::ESR, 0);
+        self.write(reg::ESR, 0);
+
+        self.write(reg::SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    /// Start the timer in `mode`, counting down from `initial_count` at the bus clock divided by
+    /// 16. The initial count is ignored in TSC-deadline mode.
+    pub fn start_timer(&self, mode: TimerMode, initial_count: u32) {
+        self.write(reg::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(reg::LVT_TIMER, mode as u32 | TIMER_VECTOR as u32);
+
+        if mode != TimerMode::TscDeadline {
+            self.write(reg::TIMER_INITIAL_COUNT, initial_count);
+        }
+    }
+
+    /// Arm the timer, which must be in TSC-deadline mode, to fire when the TSC reaches
+    /// `deadline`.
+    pub fn set_tsc_deadline(&self, deadline: u64) {
+        // SAFETY: IA32_TSC_DEADLINE is supported, as the timer is in TSC-deadline mode.
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) }
+    }
+
+    /// Stop and mask the timer.
+    pub fn stop_timer(&self) {
+        self.write(reg::LVT_TIMER, LVT_MASKED);
+        self.write(reg::TIMER_INITIAL_COUNT, 0);
+    }
+
+    /// Measure the frequency of the timer clock in Hz, busy-waiting `ns` nanoseconds with
+    /// `delay`.
+    pub fn calibrate_timer(&self, ns: u64, delay: impl FnOnce(u64)) -> u64 {
+        self.write(reg::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(reg::LVT_TIMER, LVT_MASKED);
+        self.write(reg::TIMER_INITIAL_COUNT, u32::MAX);
+
+        delay(ns);
+
+        let elapsed = u32::MAX - self.read(reg::TIMER_CURRENT_COUNT);
+        self.stop_timer();
+
+        elapsed as u64 * 1_000_000_000 / ns
+    }
+
+    /// Deliver performance counter overflows as NMIs. The entry is masked again by every
+    /// overflow, so this must be called after each one.
+    pub fn set_perf_nmi(&self) {
+        self.write(reg::LVT_PERF, LVT_DELIVERY_MODE_NMI);
+    }
+
+    /// Send an NMI to the CPU whose Local APIC ID is `apic_id`.
+    pub fn send_nmi(&self, apic_id: u32) {
+        let command = ICR_DELIVERY_MODE_NMI | ICR_LEVEL_ASSERT;
+
+        match self.mode {
+            Mode::XApic(_) => {
+                self.write(reg::ICR_HIGH, apic_id << 24);
+                self.write(reg::ICR_LOW, command);
+
+                while self.read(reg::ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            // SAFETY: x2APIC mode is enabled. The ICR is a single 64-bit MSR in x2APIC mode,
+            // with the destination in the upper half.
+            Mode::X2Apic => unsafe {
+                Msr::new(X2APIC_MSR_BASE + (reg::ICR_LOW >> 4))
+                    .write((apic_id as u64) << 32 | command as u64)
+            },
+        }
+    }
+
+    /// Read and clear the error status register.
+    pub fn error_status(&self) -> u32 {
+        self.write(reg::ESR, 0);
+        self.read(reg::ESR)
+    }
+}
+
+/// Report an internal error of the Local APIC.
+pub(super) fn error_interrupt(_: &mut TrapFrame) -> IrqReturn {
+    log::error!("local APIC error (ESR {:#X})", get().error_status());
+    IrqReturn::Handled
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// Get a handle to the Local APIC.
+///
+/// # Panics
+///
+/// This function will panic if the Local APIC has not been initialized.
+pub fn get() -> &'static LocalApic {
+    LOCAL_APIC.get().expect("local APIC not initialized")
+}
+
+/// Initialize the Local APIC, using x2APIC mode if the CPU supports it.
+pub(super) fn init(address: u64) -> &'static LocalApic {
+    LOCAL_APIC.call_once(|| {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+
+        // SAFETY: IA32_APIC_BASE is an architectural MSR and only the enable bits are changed.
+        let mode = unsafe {
+            let value = apic_base.read();
+
+            if cpu::has(Feature::X2Apic) {
+                apic_base.write(value | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE);
+                Mode::X2Apic
+            } else {
+                apic_base.write(value | APIC_BASE_GLOBAL_ENABLE);
+
+                let msr_address = value & APIC_BASE_ADDRESS_MASK;
+                if msr_address != address {
+                    log::warn!(
+                        "MADT local APIC address {address:#X} differs from IA32_APIC_BASE {msr_address:#X}"
+                    );
+                }
+
+                Mode::XApic(PhysAddr::new(address).to_virt())
+            }
+        };
+
+        LocalApic { mode }
+    })
+}
+
+/// Enable the Local APIC of an application processor in the mode chosen by [`init`] on the BSP.
+///
+/// # Panics
+///
+/// This function will panic if the Local APIC has not been initialized on the BSP.
+pub(super) fn init_ap() -> &'static LocalApic {
+    let lapic = get();
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+
+    // SAFETY: as in `init`. x2APIC mode is only chosen if the CPUs support it.
+    unsafe {
+        let value = apic_base.read() | APIC_BASE_GLOBAL_ENABLE;
+
+        if lapic.is_x2apic() {
+            apic_base.write(value | APIC_BASE_X2APIC_ENABLE);
+        } else {
+            apic_base.write(value);
+        }
+    }
+
+    lapic
+}
@@ -0,0 +1,405 @@
+//! Frame pointer based stack walking with symbol resolution from the kernel ELF.
+
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use limine::File;
+use spin::Once;
+
+/// The maximum number of frames printed in a single backtrace.
+const MAX_FRAMES: usize = 32;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+#[repr(C)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+struct Elf64Shdr {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u64,
+    sh_addr: u64,
+    sh_offset: u64,
+    sh_size: u64,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u64,
+    sh_entsize: u64,
+}
+
+#[repr(C)]
+struct Elf64Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+impl Elf64Sym {
+    fn is_function(&self) -> bool {
+        self.st_info & 0xF == STT_FUNC
+    }
+}
+
+/// The symbol table of the running kernel.
+struct SymbolTable {
+    symbols: &'static [Elf64Sym],
+    strings: &'static [u8],
+
+    /// The difference between the runtime and link-time addresses of the kernel, as the kernel
+    /// is loaded at a random address when KASLR is enabled.
+    slide: u64,
+}
+
+impl SymbolTable {
+    /// Locate the symbol and string tables in the kernel ELF.
+    fn new(elf: &'static [u8]) -> Option<Self> {
+        if elf.len() < core::mem::size_of::<Elf64Ehdr>() || &elf[..4] != b"\x7FELF" {
+            return None;
+        }
+
+        // SAFETY: the kernel file is page aligned and large enough to hold the ELF header.
+        let ehdr = unsafe { &*elf.as_ptr().cast::<Elf64Ehdr>() };
+        let shdrs_end =
+            (ehdr.e_shnum as u64 * ehdr.e_shentsize as u64).checked_add(ehdr.e_shoff)?;
+
+        if ehdr.e_shentsize as usize != core::mem::size_of::<Elf64Shdr>()
+            || shdrs_end > elf.len() as u64
+        {
+            return None;
+        }
+
+        // SAFETY: the section header table lies within the kernel file, as checked above.
+        let shdrs = unsafe {
+            core::slice::from_raw_parts(
+                elf.as_ptr().add(ehdr.e_shoff as usize).cast::<Elf64Shdr>(),
+                ehdr.e_shnum as usize,
+            )
+        };
+
+        let symtab = shdrs.iter().find(|s| s.sh_type == SHT_SYMTAB)?;
+        let strtab = shdrs.get(symtab.sh_link as usize)?;
+
+        // A section whose end overflows is as missing as one past the end of the file.
+        let section = |shdr: &Elf64Shdr| {
+            let end = shdr.sh_offset.checked_add(shdr.sh_size)?;
+            elf.get(shdr.sh_offset as usize..end as usize)
+        };
+        let symbols = section(symtab)?;
+        let strings = section(strtab)?;
+
+        // SAFETY: the symbol table lies within the kernel file and ELF mandates its alignment.
+        let symbols = unsafe {
+            core::slice::from_raw_parts(
+                symbols.as_ptr().cast::<Elf64Sym>(),
+                symbols.len() / core::mem::size_of::<Elf64Sym>(),
+            )
+        };
+
+        let mut table = Self {
+            symbols,
+            strings,
+            slide: 0,
+        };
+
+        let kinit = table
+            .symbols
+            .iter()
+            .find(|sym| table.name(sym) == Some("kinit"))?;
+        table.slide = (crate::kinit as *const () as u64).wrapping_sub(kinit.st_value);
+
+        Some(table)
+    }
+
+    /// Return the name of a symbol.
+    fn name(&self, sym: &Elf64Sym) -> Option<&'static str> {
+        let strings: &'static [u8] = self.strings;
+        let name = strings.get(sym.st_name as usize..)?;
+        let len = name.iter().position(|b| *b == 0)?;
+
+        core::str::from_utf8(&name[..len]).ok()
+    }
+
+    /// Find the function containing `addr`, returning its name and the offset of `addr` into it.
+    fn resolve(&self, addr: u64) -> Option<(&'static str, u64)> {
+        let addr = addr.wrapping_sub(self.slide);
+
+        let sym = self.symbols.iter().find(|sym| {
+            sym.is_function() && addr >= sym.st_value && addr < sym.st_value + sym.st_size
+        })?;
+
+        Some((self.name(sym)?, addr - sym.st_value))
+    }
+}
+
+static SYMBOLS: Once<SymbolTable> = Once::new();
+
+/// Set while a backtrace is being printed, to avoid recursing if walking the stack faults.
+static IN_BACKTRACE: AtomicBool = AtomicBool::new(false);
+
+/// The instruction and frame pointers of the context a fatal exception interrupted, or 0 if the
+/// next panic is not caused by one.
+static FAULT_RIP: AtomicU64 = AtomicU64::new(0);
+static FAULT_RBP: AtomicU64 = AtomicU64::new(0);
+
+/// A demangled Rust symbol using the legacy mangling scheme, with its hash removed.
+struct Demangle<'a>(&'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) else {
+            return f.write_str(self.0);
+        };
+
+        let mut first = true;
+
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let Some(len) = rest[..digits].parse::<usize>().ok() else {
+                return f.write_str(self.0);
+            };
+            let Some(component) = rest.get(digits..digits + len) else {
+                return f.write_str(self.0);
+            };
+            rest = &rest[digits + len..];
+
+            // The last component of a legacy symbol is a hash of the form `h0123456789abcdef`.
+            let is_hash = rest.is_empty()
+                && component.len() == 17
+                && component.starts_with('h')
+                && component[1..].bytes().all(|b| b.is_ascii_hexdigit());
+
+            if is_hash {
+                break;
+            }
+
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+
+            write_component(
+                f,
+                component
+                    .strip_prefix('_')
+                    .filter(|c| c.starts_with('$'))
+                    .unwrap_or(component),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write a path component, decoding the escapes used by the legacy mangling scheme.
+fn write_component(f: &mut fmt::Formatter<'_>, mut component: &str) -> fmt::Result {
+    while !component.is_empty() {
+        if let Some(rest) = component.strip_prefix("..") {
+            f.write_str("::")?;
+            component = rest;
+            continue;
+        }
+
+        if component.starts_with('$') {
+            if let Some(end) = component[1..].find('$') {
+                let escape = &component[1..end + 1];
+
+                let decoded = match escape {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C" => Some(','),
+                    _ => escape
+                        .strip_prefix('u')
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32),
+                };
+
+                if let Some(ch) = decoded {
+                    fmt::Write::write_char(f, ch)?;
+                    component = &component[end + 2..];
+                    continue;
+                }
+            }
+        }
+
+        let len = component.chars().next().map_or(1, char::len_utf8);
+        f.write_str(&component[..len])?;
+        component = &component[len..];
+    }
+
+    Ok(())
+}
+
+/// Print a single frame of a backtrace.
+fn print_frame(index: usize, addr: u64) {
+    match SYMBOLS.get().and_then(|symbols| symbols.resolve(addr)) {
+        Some((name, offset)) => log::error!("#{index} {}+{offset:#x}", Demangle(name)),
+        None => log::error!("#{index} {addr:#018x} <unknown>"),
+    }
+}
+
+/// Check if `rbp` can be dereferenced as a saved frame pointer.
+fn is_valid_frame(rbp: u64) -> bool {
+    // Kernel stacks only ever live in the higher half.
+    rbp != 0 && rbp % 8 == 0 && rbp >= 0xFFFF_8000_0000_0000
+}
+
+/// The return addresses found by following the chain of saved frame pointers.
+struct Frames {
+    rbp: u64,
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if !is_valid_frame(self.rbp) {
+            return None;
+        }
+
+        // SAFETY: with frame pointers enabled, `rbp` points to the previous frame pointer,
+        // followed by the return address of the current frame.
+        let (next, ret) = unsafe {
+            let frame = self.rbp as *const u64;
+            (*frame, *frame.add(1))
+        };
+
+        if ret == 0 {
+            return None;
+        }
+
+        self.rbp = next;
+
+        // The return address points after the call, so resolve the call instruction instead.
+        Some(ret - 1)
+    }
+}
+
+/// Read the frame pointer of the caller, which this is inlined into.
+#[inline(always)]
+fn current_rbp() -> u64 {
+    let rbp: u64;
+    // SAFETY: reading the frame pointer has no side effects.
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+
+    rbp
+}
+
+/// Walk the chain of saved frame pointers starting at `rbp`, printing every return address.
+fn walk(index: usize, rbp: u64) {
+    for (index, addr) in (index..MAX_FRAMES).zip(Frames { rbp }) {
+        print_frame(index, addr);
+    }
+}
+
+/// Print a backtrace of the current call stack.
+pub fn print() {
+    if IN_BACKTRACE.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    log::error!("backtrace:");
+    walk(0, current_rbp());
+
+    IN_BACKTRACE.store(false, Ordering::Release);
+}
+
+/// Print a backtrace of an interrupted context, starting at the instruction it was executing.
+pub fn print_from(rip: u64, rbp: u64) {
+    if IN_BACKTRACE.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    log::error!("backtrace:");
+    print_frame(0, rip);
+    walk(1, rbp);
+
+    IN_BACKTRACE.store(false, Ordering::Release);
+}
+
+/// Make the next panic print a backtrace of the context interrupted at `rip` with the frame
+/// pointer `rbp`, rather than of the exception handler that panics.
+pub fn set_fault_context(rip: u64, rbp: u64) {
+    FAULT_RBP.store(rbp, Ordering::Relaxed);
+    FAULT_RIP.store(rip, Ordering::Release);
+}
+
+/// Print the backtrace of a panic: that of the interrupted context if a fatal exception caused
+/// it, or of the current call stack otherwise.
+pub fn print_panic() {
+    match FAULT_RIP.swap(0, Ordering::Acquire) {
+        0 => print(),
+        rip => print_from(rip, FAULT_RBP.load(Ordering::Relaxed)),
+    }
+}
+
+/// Record the addresses of the current call stack into `frames`, returning how many were
+/// recorded. The frame of the caller comes first.
+pub fn capture(frames: &mut [u64]) -> usize {
+    frames
+        .iter_mut()
+        .zip(Frames { rbp: current_rbp() })
+        .map(|(frame, addr)| *frame = addr)
+        .count()
+}
+
+/// Print a backtrace recorded by [`capture`].
+pub fn print_captured(frames: &[u64]) {
+    for (index, &addr) in frames.iter().enumerate() {
+        print_frame(index, addr);
+    }
+}
+
+/// Initialize symbol resolution using the kernel ELF file loaded by the bootloader, if it
+/// provided one.
+pub fn init(kernel_file: Option<&File>) {
+    let Some((base, kernel_file)) = kernel_file.and_then(|file| Some((file.base.as_ptr()?, file)))
+    else {
+        log::warn!("kernel file is unavailable, backtraces will not be symbolized");
+        return;
+    };
+
+    // SAFETY: the bootloader provides the kernel file as a contiguous region of memory that is
+    // never reclaimed.
+    let elf = unsafe { core::slice::from_raw_parts(base, kernel_file.length as usize) };
+
+    match SymbolTable::new(elf) {
+        Some(table) => {
+            let symbols = SYMBOLS.call_once(|| table);
+            log::info!(
+                "loaded {} kernel symbol(s) (slide {:#X})",
+                symbols.symbols.len(),
+                symbols.slide
+            );
+        }
+        None => log::warn!("no symbol table found in the kernel file"),
+    }
+}
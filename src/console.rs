@@ -0,0 +1,70 @@
+//! A debug console on the serial port.
+//!
+//! Every key received on COM1 names a command, which runs from the system workqueue so that it
+//! may print as much as it needs to. Press `h` for the list of commands.
+
+use crate::{
+    drivers::uart::{self, serial_print},
+    idt::TrapFrame,
+    irq::{self, IrqReturn},
+    workqueue,
+};
+
+/// The ISA IRQ of COM1.
+const COM1_IRQ: u8 = 4;
+
+struct Command {
+    key: u8,
+    help: &'static str,
+    run: fn(),
+}
+
+const COMMANDS: [Command; 2] = [
+    Command {
+        key: b'h',
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        key: b'i',
+        help: "show the interrupts received by every CPU",
+        run: irq::stats::print,
+    },
+];
+
+fn help() {
+    serial_print!("debug console commands:\n");
+
+    for command in &COMMANDS {
+        serial_print!("  {}  {}\n", command.key as char, command.help);
+    }
+}
+
+/// Queue the commands for the keys waiting in the receive buffer of COM1.
+fn interrupt(_: &mut TrapFrame) -> IrqReturn {
+    let mut received = false;
+
+    loop {
+        let Ok(key) = uart::SERIAL1.get().unwrap().lock().try_receive() else {
+            break;
+        };
+
+        received = true;
+
+        if let Some(command) = COMMANDS.iter().find(|command| command.key == key) {
+            workqueue::queue(command.run);
+        }
+    }
+
+    if received {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
+}
+
+/// Start taking commands from the serial port.
+pub fn init() {
+    irq::register(COM1_IRQ, interrupt, "console")
+        .expect("unable to register the debug console handler");
+}
@@ -1,10 +1,533 @@
-//! Necessary CPU state setup for x86-64.
+//! CPU identification, feature detection, FPU/SIMD state management and the protection of user
+//! pages from the kernel.
+//!
+//! The kernel itself is built without SSE, so the FPU, SSE and AVX registers only ever hold user
+//! state. [`FpuState`] holds a saved copy of them, for a context switch to save those of the
+//! outgoing context and restore those of the incoming one eagerly.
 
-pub mod gdt;
-pub mod idt;
+use crate::percpu;
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count},
+    },
+    fmt,
+    ptr::NonNull,
+    str,
+};
+use spin::Once;
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
 
-/// Initialize CPU state and structures.
+/// The size of the FXSAVE area.
+const FXSAVE_SIZE: usize = 512;
+
+/// Offsets in the legacy region shared by the FXSAVE and XSAVE areas.
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+/// The x87 control word after `fninit`, with every exception masked.
+const DEFAULT_FCW: u16 = 0x037F;
+
+/// The MXCSR value at reset, with every exception masked.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other,
+}
+
+/// The CPUID output registers features are reported in.
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Leaf1Ecx,
+    Leaf1Edx,
+    Leaf7Ebx,
+    Leaf7Ecx,
+    Leaf7Edx,
+    LeafDEax,
+    Extended1Ecx,
+    Extended1Edx,
+    Extended7Edx,
+}
+
+const REGISTERS: usize = 9;
+
+/// CPU features that can be queried with [`has`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Msr,
+    Pae,
+    Mce,
+    Apic,
+    Pge,
+    Mca,
+    Pat,
+    Fxsr,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Fma,
+    Pcid,
+    Sse41,
+    Sse42,
+    X2Apic,
+    Popcnt,
+    TscDeadline,
+    Xsave,
+    Avx,
+    Rdrand,
+    Hypervisor,
+    FsGsBase,
+    Bmi1,
+    Avx2,
+    Smep,
+    Bmi2,
+    Invpcid,
+    Avx512F,
+    Rdseed,
+    Smap,
+    Umip,
+    Pku,
+    CetShadowStack,
+    CetIbt,
+    XsaveOpt,
+    Xsavec,
+    Xsaves,
+    Syscall,
+    Nx,
+    Page1Gb,
+    Rdtscp,
+    LongMode,
+    InvariantTsc,
+}
+
+impl Feature {
+    /// Return the register and bit the feature is reported in.
+    const fn location(self) -> (Register, u32) {
+        use Feature::*;
+        use Register::*;
+
+        match self {
+            Fpu => (Leaf1Edx, 0),
+            Tsc => (Leaf1Edx, 4),
+            Msr => (Leaf1Edx, 5),
+            Pae => (Leaf1Edx, 6),
+            Mce => (Leaf1Edx, 7),
+            Apic => (Leaf1Edx, 9),
+            Pge => (Leaf1Edx, 13),
+            Mca => (Leaf1Edx, 14),
+            Pat => (Leaf1Edx, 16),
+            Fxsr => (Leaf1Edx, 24),
+            Sse => (Leaf1Edx, 25),
+            Sse2 => (Leaf1Edx, 26),
+            Sse3 => (Leaf1Ecx, 0),
+            Ssse3 => (Leaf1Ecx, 9),
+            Fma => (Leaf1Ecx, 12),
+            Pcid => (Leaf1Ecx, 17),
+            Sse41 => (Leaf1Ecx, 19),
+            Sse42 => (Leaf1Ecx, 20),
+            X2Apic => (Leaf1Ecx, 21),
+            Popcnt => (Leaf1Ecx, 23),
+            TscDeadline => (Leaf1Ecx, 24),
+            Xsave => (Leaf1Ecx, 26),
+            Avx => (Leaf1Ecx, 28),
+            Rdrand => (Leaf1Ecx, 30),
+            Hypervisor => (Leaf1Ecx, 31),
+            FsGsBase => (Leaf7Ebx, 0),
+            Bmi1 => (Leaf7Ebx, 3),
+            Avx2 => (Leaf7Ebx, 5),
+            Smep => (Leaf7Ebx, 7),
+            Bmi2 => (Leaf7Ebx, 8),
+            Invpcid => (Leaf7Ebx, 10),
+            Avx512F => (Leaf7Ebx, 16),
+            Rdseed => (Leaf7Ebx, 18),
+            Smap => (Leaf7Ebx, 20),
+            Umip => (Leaf7Ecx, 2),
+            Pku => (Leaf7Ecx, 3),
+            CetShadowStack => (Leaf7Ecx, 7),
+            CetIbt => (Leaf7Edx, 20),
+            XsaveOpt => (LeafDEax, 0),
+            Xsavec => (LeafDEax, 1),
+            Xsaves => (LeafDEax, 3),
+            Syscall => (Extended1Edx, 11),
+            Nx => (Extended1Edx, 20),
+            Page1Gb => (Extended1Edx, 26),
+            Rdtscp => (Extended1Edx, 27),
+            LongMode => (Extended1Edx, 29),
+            InvariantTsc => (Extended7Edx, 8),
+        }
+    }
+}
+
+/// The architectural performance monitoring unit, as reported by CPUID leaf 0xA.
+#[derive(Debug, Clone, Copy)]
+pub struct Perfmon {
+    pub version: u8,
+
+    /// The amount of general-purpose counters.
+    pub counters: u8,
+
+    /// The width of the general-purpose counters in bits.
+    pub counter_width: u8,
+
+    /// Whether the unhalted core cycles event is available.
+    pub core_cycles: bool,
+}
+
+/// What CPUID reports about the CPU.
+pub struct CpuInfo {
+    pub vendor: Vendor,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    brand: [u8; 48],
+    registers: [u32; REGISTERS],
+
+    /// The state components XCR0 may enable.
+    pub xstate_supported: u64,
+
+    /// The size of the XSAVE area with every supported component enabled.
+    pub xsave_max_size: u32,
+
+    /// The performance monitoring unit, if the CPU has an architectural one.
+    pub perfmon: Option<Perfmon>,
+}
+
+impl CpuInfo {
+    fn probe() -> Self {
+        // SAFETY: leaves 0 and 0x80000000 are supported by every x86-64 CPU, and other leaves
+        // are only queried if they are reported as supported.
+        let cpuid = |leaf| unsafe { __cpuid(leaf) };
+        let cpuid_count = |leaf, subleaf| unsafe { __cpuid_count(leaf, subleaf) };
+
+        let leaf0 = cpuid(0);
+        let max_leaf = leaf0.eax;
+        let max_extended_leaf = cpuid(0x8000_0000).eax;
+
+        let mut vendor_id = [0; 12];
+        vendor_id[..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor_id[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor_id[8..].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        let vendor = match &vendor_id {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other,
+        };
+
+        let leaf1 = cpuid(1);
+        let base_family = (leaf1.eax >> 8) & 0xF;
+        let base_model = (leaf1.eax >> 4) & 0xF;
+
+        let family = match base_family {
+            0xF => base_family + ((leaf1.eax >> 20) & 0xFF),
+            _ => base_family,
+        };
+        let model = match base_family {
+            0x6 | 0xF => base_model | ((leaf1.eax >> 12) & 0xF0),
+            _ => base_model,
+        };
+
+        let mut registers = [0; REGISTERS];
+        registers[Register::Leaf1Ecx as usize] = leaf1.ecx;
+        registers[Register::Leaf1Edx as usize] = leaf1.edx;
+
+        if max_leaf >= 7 {
+            let leaf7 = cpuid_count(7, 0);
+            registers[Register::Leaf7Ebx as usize] = leaf7.ebx;
+            registers[Register::Leaf7Ecx as usize] = leaf7.ecx;
+            registers[Register::Leaf7Edx as usize] = leaf7.edx;
+        }
+
+        let (xstate_supported, xsave_max_size) = if max_leaf >= 0xD {
+            let components = cpuid_count(0xD, 0);
+            registers[Register::LeafDEax as usize] = cpuid_count(0xD, 1).eax;
+
+            (
+                components.eax as u64 | (components.edx as u64) << 32,
+                components.ecx,
+            )
+        } else {
+            (0, 0)
+        };
+
+        let perfmon = (max_leaf >= 0xA)
+            .then(|| cpuid(0xA))
+            .filter(|leaf| leaf.eax & 0xFF != 0)
+            .map(|leaf| Perfmon {
+                version: leaf.eax as u8,
+                counters: (leaf.eax >> 8) as u8,
+                counter_width: (leaf.eax >> 16) as u8,
+                // EBX flags events that are not available, for as many events as EAX lists.
+                core_cycles: (leaf.eax >> 24) > 0 && leaf.ebx & 1 == 0,
+            });
+
+        if max_extended_leaf >= 0x8000_0001 {
+            let extended1 = cpuid(0x8000_0001);
+            registers[Register::Extended1Ecx as usize] = extended1.ecx;
+            registers[Register::Extended1Edx as usize] = extended1.edx;
+        }
+
+        if max_extended_leaf >= 0x8000_0007 {
+            registers[Register::Extended7Edx as usize] = cpuid(0x8000_0007).edx;
+        }
+
+        let mut brand = [0; 48];
+        if max_extended_leaf >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let result = cpuid(leaf);
+
+                for (j, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                    .into_iter()
+                    .enumerate()
+                {
+                    let offset = i * 16 + j * 4;
+                    brand[offset..offset + 4].copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        Self {
+            vendor,
+            family,
+            model,
+            stepping: leaf1.eax & 0xF,
+            brand,
+            registers,
+            xstate_supported,
+            xsave_max_size,
+            perfmon,
+        }
+    }
+
+    /// Return the brand string of the CPU, or an empty string if it does not report one.
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(48);
+        str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        let (register, bit) = feature.location();
+        self.registers[register as usize] & (1 << bit) != 0
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:?} family {:#X} model {:#X} stepping {})",
+            self.brand(),
+            self.vendor,
+            self.family,
+            self.model,
+            self.stepping
+        )
+    }
+}
+
+static INFO: Once<CpuInfo> = Once::new();
+
+/// Return what CPUID reports about the CPU. All CPUs are assumed to be identical.
+pub fn info() -> &'static CpuInfo {
+    INFO.call_once(CpuInfo::probe)
+}
+
+/// Check if the CPU supports `feature`.
+pub fn has(feature: Feature) -> bool {
+    info().has(feature)
+}
+
+/// How the FPU and SIMD registers are saved, decided once XCR0 has been set up.
+struct StateFormat {
+    xsave: bool,
+
+    /// The size of the save area.
+    size: usize,
+}
+
+static STATE_FORMAT: Once<StateFormat> = Once::new();
+
+fn state_format() -> &'static StateFormat {
+    STATE_FORMAT.get().expect("FPU not initialized")
+}
+
+/// The saved FPU, SSE and AVX registers of a context.
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+// SAFETY: the save area is owned by the state.
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    fn layout() -> Layout {
+        // XSAVE requires 64-byte alignment and FXSAVE 16-byte alignment.
+        Layout::from_size_align(state_format().size, 64).unwrap()
+    }
+
+    /// Create a state with every register in its initial configuration.
+    pub fn new() -> Self {
+        let layout = Self::layout();
+
+        // SAFETY: the layout has a non-zero size.
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+
+        // SAFETY: the area is large enough for the legacy region. With the XSAVE header zeroed,
+        // XRSTOR puts every component other than MXCSR in its initial configuration.
+        unsafe {
+            area.as_ptr()
+                .add(FCW_OFFSET)
+                .cast::<u16>()
+                .write(DEFAULT_FCW);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(DEFAULT_MXCSR);
+        }
+
+        Self { area }
+    }
+
+    /// Save the registers of the current CPU.
+    pub fn save(&mut self) {
+        let area = self.area.as_ptr();
+
+        // SAFETY: the area is suitably sized and aligned for the instruction used, which is
+        // supported as checked by `init`.
+        unsafe {
+            if state_format().xsave {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+            }
+        }
+    }
+
+    /// Load the registers saved by [`FpuState::save`] into the current CPU.
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+
+        // SAFETY: as above. The area holds either the initial state or one saved by `save`.
+        unsafe {
+            if state_format().xsave {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, readonly));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // SAFETY: the area was allocated in `new` with the same layout.
+        unsafe { dealloc(self.area.as_ptr(), Self::layout()) }
+    }
+}
+
+/// Enable the FPU, SSE, and AVX, XSAVE, SMEP, SMAP and UMIP where supported, on the current
+/// CPU.
 pub fn init() {
-    gdt::init();
-    idt::init();
+    let info = info();
+
+    // SAFETY: the FPU and SSE are present on every x86-64 CPU, and XSAVE and AVX are only
+    // enabled if supported.
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+
+        Cr4::update(|cr4| {
+            cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+
+            if info.has(Feature::Xsave) {
+                cr4.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+
+        asm!("fninit", options(nomem, nostack));
+    }
+
+    // Keep the kernel from executing user pages, and from accessing them outside of
+    // `mem::user`, and user mode from reading the descriptor tables.
+    let hardening = [
+        (
+            Feature::Smep,
+            Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+            "SMEP",
+        ),
+        (
+            Feature::Smap,
+            Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+            "SMAP",
+        ),
+        (
+            Feature::Umip,
+            Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION,
+            "UMIP",
+        ),
+    ];
+
+    for (feature, flag, name) in hardening {
+        if info.has(feature) {
+            // SAFETY: the kernel does not execute or access user pages other than through
+            // `mem::user`, nor use the instructions UMIP restricts from user mode.
+            unsafe { Cr4::update(|cr4| cr4.insert(flag)) };
+            log::debug!("enabled {name} on CPU {}", percpu::current_cpu());
+        }
+    }
+
+    let xsave = info.has(Feature::Xsave);
+
+    if xsave {
+        let supported = XCr0Flags::from_bits_truncate(info.xstate_supported);
+        let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+
+        if info.has(Feature::Avx) && supported.contains(XCr0Flags::AVX) {
+            xcr0 |= XCr0Flags::AVX;
+        }
+
+        let avx512 = XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+        if info.has(Feature::Avx512F) && supported.contains(avx512) {
+            xcr0 |= avx512;
+        }
+
+        // SAFETY: only components reported as supported are enabled.
+        unsafe { XCr0::write(xcr0) };
+    }
+
+    let format = STATE_FORMAT.call_once(|| StateFormat {
+        xsave,
+        size: if xsave {
+            // SAFETY: leaf 0xD is supported if XSAVE is. EBX reports the size required by the
+            // components currently enabled in XCR0.
+            unsafe { __cpuid_count(0xD, 0) }.ebx as usize
+        } else {
+            FXSAVE_SIZE
+        },
+    });
+
+    log::info!(
+        "enabled FPU state saving with {} ({} bytes)",
+        if format.xsave { "XSAVE" } else { "FXSAVE" },
+        format.size
+    );
 }
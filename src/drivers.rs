@@ -1,4 +1,8 @@
 //! OS drivers
 
 pub mod graphics;
+pub mod hpet;
+pub mod pci;
+pub mod pit;
+pub mod rtc;
 pub mod uart;
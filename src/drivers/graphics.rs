@@ -1,8 +1,9 @@
 //! Graphics driver using the Limine framebuffer.
 
+use crate::{logger, sync::IrqSpinLock};
 use core::fmt::{self, Arguments, Write};
 use limine::Framebuffer;
-use spin::{Mutex, Once};
+use spin::Once;
 
 const MARGIN: usize = 32;
 const FONT_WIDTH: usize = 8;
@@ -141,11 +142,14 @@ impl Write for Writer {
     }
 }
 
-pub static WRITER: Once<Mutex<Writer>> = Once::new();
+pub static WRITER: Once<IrqSpinLock<Writer>> = Once::new();
 
 #[doc(hidden)]
 pub fn _print(args: Arguments<'_>) {
-    WRITER.get().map(|l| l.lock().write_fmt(args));
+    WRITER.get().map(|l| match logger::bust_spins() {
+        Some(spins) => l.lock_or_bust(spins).write_fmt(args),
+        None => l.lock().write_fmt(args),
+    });
 }
 
 pub macro print($($arg:tt)*) {
@@ -171,5 +175,5 @@ pub fn init(fb: &Framebuffer) {
 
     let writer = Writer::new(fb_slice, fb_info);
 
-    WRITER.call_once(|| Mutex::new(writer));
+    WRITER.call_once(|| IrqSpinLock::new(writer));
 }
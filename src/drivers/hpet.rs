@@ -0,0 +1,330 @@
+//! High Precision Event Timer (HPET) driver.
+
+use crate::mem::PhysToVirt;
+use acpi::HpetInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const GENERAL_INTERRUPT_STATUS: u64 = 0x020;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+const fn timer_configuration(n: u8) -> u64 {
+    0x100 + 0x20 * n as u64
+}
+
+const fn timer_comparator(n: u8) -> u64 {
+    0x108 + 0x20 * n as u64
+}
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CAP_NUM_TIMERS_SHIFT: u64 = 8;
+const CAP_PERIOD_SHIFT: u64 = 32;
+
+const CONF_ENABLE: u64 = 1 << 0;
+const CONF_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_ROUTE_CAPABILITIES_SHIFT: u64 = 32;
+
+/// The maximum counter period allowed by the specification, 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const FS_PER_NS: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub struct Hpet {
+    /// The virtual address of the register space of the HPET.
+    base: VirtAddr,
+
+    /// The period of the main counter in femtoseconds.
+    period_fs: u64,
+
+    /// The amount of comparators implemented.
+    comparators: u8,
+
+    /// Whether the main counter is 64 bits wide rather than 32.
+    counter_64bit: bool,
+
+    /// Whether comparators 0 and 1 can replace the PIT on IRQ0 and the RTC on IRQ8.
+    legacy_route_capable: bool,
+
+    /// The main counter extended to 64 bits, for HPETs with a 32-bit counter.
+    extended_counter: AtomicU64,
+}
+
+impl Hpet {
+    fn read(&self, reg: u64) -> u64 {
+        // SAFETY: `base` maps the register space of the HPET and `reg` is a valid offset.
+        unsafe { core::ptr::read_volatile((self.base + reg).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, reg: u64, value: u64) {
+        // SAFETY: `base` maps the register space of the HPET and `reg` is a valid offset.
+        unsafe { core::ptr::write_volatile((self.base + reg).as_mut_ptr::<u64>(), value) }
+    }
+
+    /// Return the frequency of the main counter in Hz.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    /// Return the period of the main counter in femtoseconds.
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    /// Return the amount of comparators implemented.
+    pub fn comparators(&self) -> u8 {
+        self.comparators
+    }
+
+    /// Check if comparators 0 and 1 can replace the PIT and RTC through legacy routing.
+    pub fn is_legacy_route_capable(&self) -> bool {
+        self.legacy_route_capable
+    }
+
+    /// Read the main counter.
+    ///
+    /// A 32-bit counter is extended to 64 bits in software, which relies on the counter being read
+    /// at least once per wraparound (about five minutes at 14.318 MHz).
+    pub fn counter(&self) -> u64 {
+        let raw = self.read(MAIN_COUNTER);
+
+        if self.counter_64bit {
+            return raw;
+        }
+
+        let raw = raw & 0xFFFF_FFFF;
+        let mut last = self.extended_counter.load(Ordering::Relaxed);
+
+        loop {
+            let mut extended = (last & !0xFFFF_FFFF) | raw;
+            if extended < last {
+                extended += 1 << 32;
+            }
+
+            match self.extended_counter.compare_exchange_weak(
+                last,
+                extended,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return extended,
+                // Another CPU observed a later value, which is at least as recent as ours.
+                Err(current) if current >= extended => return current,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    /// Convert a duration in nanoseconds to counter ticks, rounding up.
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        ((ns as u128 * FS_PER_NS as u128).div_ceil(self.period_fs as u128)) as u64
+    }
+
+    /// Convert counter ticks to nanoseconds.
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FS_PER_NS as u128) as u64
+    }
+
+    /// Return the time elapsed since the main counter was started, in nanoseconds.
+    pub fn nanoseconds(&self) -> u64 {
+        self.ticks_to_ns(self.counter())
+    }
+
+    /// Busy-wait for at least `ns` nanoseconds.
+    pub fn wait(&self, ns: u64) {
+        let deadline = self.counter() + self.ns_to_ticks(ns);
+
+        while self.counter() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Route comparator 0 to IRQ0 and comparator 1 to IRQ8 in place of the PIT and RTC.
+    ///
+    /// Returns `false` if the HPET does not support legacy replacement routing.
+    pub fn enable_legacy_routing(&self) -> bool {
+        if !self.legacy_route_capable {
+            return false;
+        }
+
+        let config = self.read(GENERAL_CONFIGURATION);
+        self.write(GENERAL_CONFIGURATION, config | CONF_LEGACY_ROUTE);
+
+        true
+    }
+
+    /// Give IRQ0 and IRQ8 back to the PIT and RTC.
+    pub fn disable_legacy_routing(&self) {
+        let config = self.read(GENERAL_CONFIGURATION);
+        self.write(GENERAL_CONFIGURATION, config & !CONF_LEGACY_ROUTE);
+    }
+
+    /// Return a handle to comparator `n`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the comparator is not implemented.
+    pub fn comparator(&self, n: u8) -> Comparator<'_> {
+        assert!(n < self.comparators, "hpet: no comparator {n}");
+        Comparator { hpet: self, n }
+    }
+
+    /// Acknowledge a level-triggered interrupt raised by comparator `n`.
+    pub fn acknowledge(&self, n: u8) {
+        self.write(GENERAL_INTERRUPT_STATUS, 1 << n);
+    }
+}
+
+/// One of the comparators of the HPET, which raises an interrupt when the main counter reaches
+/// its value.
+#[derive(Debug, Clone, Copy)]
+pub struct Comparator<'a> {
+    hpet: &'a Hpet,
+    n: u8,
+}
+
+impl Comparator<'_> {
+    fn configuration(&self) -> u64 {
+        self.hpet.read(timer_configuration(self.n))
+    }
+
+    fn set_configuration(&self, value: u64) {
+        self.hpet.write(timer_configuration(self.n), value)
+    }
+
+    /// Check if the comparator can fire periodically.
+    pub fn is_periodic_capable(&self) -> bool {
+        self.configuration() & TIMER_PERIODIC_CAPABLE != 0
+    }
+
+    /// Return the bitmap of I/O APIC inputs this comparator can be routed to.
+    pub fn route_capabilities(&self) -> u32 {
+        (self.configuration() >> TIMER_ROUTE_CAPABILITIES_SHIFT) as u32
+    }
+
+    /// Route the interrupt of this comparator to the I/O APIC input `gsi`, as edge triggered.
+    ///
+    /// Ignored for comparators 0 and 1 while legacy replacement routing is enabled.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the comparator cannot be routed to `gsi`.
+    pub fn route(&self, gsi: u8) {
+        assert!(
+            gsi < 32 && self.route_capabilities() & (1 << gsi) != 0,
+            "hpet: comparator {} cannot be routed to GSI {gsi}",
+            self.n
+        );
+
+        let config =
+            self.configuration() & !(TIMER_ROUTE_MASK | TIMER_FSB_ENABLE | TIMER_LEVEL_TRIGGERED);
+        self.set_configuration(config | (gsi as u64) << TIMER_ROUTE_SHIFT);
+    }
+
+    /// Fire a single interrupt `ns` nanoseconds from now.
+    pub fn one_shot(&self, ns: u64) {
+        let config = self.configuration() & !(TIMER_PERIODIC | TIMER_32BIT_MODE);
+        self.set_configuration(config | TIMER_INTERRUPT_ENABLE);
+
+        let deadline = self.hpet.counter() + self.hpet.ns_to_ticks(ns);
+        self.hpet.write(timer_comparator(self.n), deadline);
+    }
+
+    /// Fire an interrupt every `ns` nanoseconds.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the comparator cannot fire periodically.
+    pub fn periodic(&self, ns: u64) {
+        assert!(
+            self.is_periodic_capable(),
+            "hpet: comparator {} is not periodic capable",
+            self.n
+        );
+
+        let period = self.hpet.ns_to_ticks(ns);
+        let config = self.configuration() & !TIMER_32BIT_MODE;
+        self.set_configuration(config | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET);
+
+        // With `TIMER_VALUE_SET`, the first write sets the comparator and the second the period.
+        self.hpet
+            .write(timer_comparator(self.n), self.hpet.counter() + period);
+        self.hpet.write(timer_comparator(self.n), period);
+    }
+
+    /// Stop the comparator from raising interrupts.
+    pub fn stop(&self) {
+        let config = self.configuration() & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC);
+        self.set_configuration(config);
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// Get a handle to the HPET, or `None` if the system does not have one.
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// Initialize the HPET described by the ACPI HPET table and start its main counter.
+pub fn init(info: Option<HpetInfo>) {
+    let Some(info) = info else {
+        log::warn!("hpet: no HPET table found");
+        return;
+    };
+
+    let base = PhysAddr::new(info.base_address as u64).to_virt();
+
+    // SAFETY: the HPET table describes the register space of the HPET, which is mapped by the
+    // HHDM.
+    let capabilities =
+        unsafe { core::ptr::read_volatile((base + GENERAL_CAPABILITIES).as_ptr::<u64>()) };
+
+    let period_fs = capabilities >> CAP_PERIOD_SHIFT;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        log::warn!("hpet: invalid counter period {period_fs} fs");
+        return;
+    }
+
+    let hpet = HPET.call_once(|| Hpet {
+        base,
+        period_fs,
+        comparators: ((capabilities >> CAP_NUM_TIMERS_SHIFT) & 0x1F) as u8 + 1,
+        counter_64bit: capabilities & CAP_COUNTER_64BIT != 0,
+        legacy_route_capable: capabilities & CAP_LEGACY_ROUTE != 0,
+        extended_counter: AtomicU64::new(0),
+    });
+
+    // Halt the counter and every comparator before resetting it.
+    let config = hpet.read(GENERAL_CONFIGURATION) & !(CONF_ENABLE | CONF_LEGACY_ROUTE);
+    hpet.write(GENERAL_CONFIGURATION, config);
+
+    for n in 0..hpet.comparators {
+        hpet.comparator(n).stop();
+    }
+
+    hpet.write(MAIN_COUNTER, 0);
+    hpet.write(GENERAL_CONFIGURATION, config | CONF_ENABLE);
+
+    log::info!(
+        "HPET at {:#X}: {} Hz, {} comparator(s), {}-bit counter",
+        info.base_address,
+        hpet.frequency(),
+        hpet.comparators,
+        if hpet.counter_64bit { 64 } else { 32 }
+    );
+}
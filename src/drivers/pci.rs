@@ -0,0 +1,249 @@
+//! PCI configuration space access through the legacy `0xCF8`/`0xCFC` I/O ports.
+
+pub mod msi;
+
+use crate::sync::IrqSpinLock;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_BAR0: u16 = 0x10;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT_LINE: u16 = 0x3C;
+const REG_INTERRUPT_PIN: u16 = 0x3D;
+
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+const BAR_IO_SPACE: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64BIT: u32 = 0b10 << 1;
+const BAR_MEMORY_ADDRESS_MASK: u32 = !0xF;
+
+/// The most capabilities that fit in configuration space, to stop at a malformed list.
+const MAX_CAPABILITIES: usize = 48;
+
+/// The address and data ports, which must be accessed as a pair.
+static CONFIG_PORTS: IrqSpinLock<(Port<u32>, Port<u32>)> =
+    IrqSpinLock::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+/// The location of a function on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Return the value to write to `CONFIG_ADDRESS` to select the dword containing `offset`.
+    fn config_address(&self, offset: u16) -> u32 {
+        CONFIG_ADDRESS_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32 & 0x1F) << 11
+            | (self.function as u32 & 0x7) << 8
+            | (offset as u32 & 0xFC)
+    }
+
+    /// Read the dword of configuration space containing `offset`.
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        let (address, data) = &mut *CONFIG_PORTS.lock();
+
+        // SAFETY: these are the standard PCI configuration ports.
+        unsafe {
+            address.write(self.config_address(offset));
+            data.read()
+        }
+    }
+
+    /// Write the dword of configuration space containing `offset`.
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        let (address, data) = &mut *CONFIG_PORTS.lock();
+
+        // SAFETY: these are the standard PCI configuration ports.
+        unsafe {
+            address.write(self.config_address(offset));
+            data.write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    /// Write a word of configuration space, preserving the other half of its dword.
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 0b10) * 8;
+        let dword = self.read_u32(offset) & !(0xFFFF << shift);
+
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+
+    /// Write a byte of configuration space, preserving the rest of its dword.
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        let shift = (offset & 0b11) * 8;
+        let dword = self.read_u32(offset) & !(0xFF << shift);
+
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+
+    /// Set and clear bits of the command register.
+    pub fn update_command(&self, set: u16, clear: u16) {
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u16(REG_COMMAND, (command | set) & !clear);
+    }
+
+    /// Return the physical address of memory BAR `index`, or `None` if it is an I/O BAR.
+    pub fn memory_bar(&self, index: u8) -> Option<PhysAddr> {
+        let offset = REG_BAR0 + index as u16 * 4;
+        let bar = self.read_u32(offset);
+
+        if bar & BAR_IO_SPACE != 0 {
+            return None;
+        }
+
+        let mut address = (bar & BAR_MEMORY_ADDRESS_MASK) as u64;
+        if bar & BAR_TYPE_MASK == BAR_TYPE_64BIT {
+            address |= (self.read_u32(offset + 4) as u64) << 32;
+        }
+
+        Some(PhysAddr::new(address))
+    }
+
+    /// Return the capabilities of the function.
+    pub fn capabilities(&self) -> Capabilities {
+        let offset = if self.read_u16(REG_STATUS) & STATUS_CAPABILITIES_LIST != 0 {
+            self.read_u8(REG_CAPABILITIES)
+        } else {
+            0
+        };
+
+        Capabilities {
+            address: *self,
+            offset,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Return the offset of the first capability with ID `id`.
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    /// Return the ISA IRQ the firmware routed the INTx pin of the function to, if it uses one.
+    pub fn interrupt_line(&self) -> Option<u8> {
+        if self.read_u8(REG_INTERRUPT_PIN) == 0 {
+            return None;
+        }
+
+        // 0xFF means unknown or not connected.
+        Some(self.read_u8(REG_INTERRUPT_LINE)).filter(|&line| line < 16)
+    }
+}
+
+/// An entry of the capability list in configuration space.
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+
+    /// The offset of the capability in configuration space.
+    pub offset: u16,
+}
+
+/// An iterator over the capabilities of a function.
+pub struct Capabilities {
+    address: PciAddress,
+    offset: u8,
+    remaining: usize,
+}
+
+impl Capabilities {
+    /// Return the current capability and move to the next, reading configuration space through
+    /// `read`.
+    fn advance(&mut self, read: impl Fn(u16) -> u8) -> Option<Capability> {
+        // The bottom two bits of the pointers are reserved.
+        let offset = (self.offset & !0b11) as u16;
+        if offset == 0 || self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+        self.offset = read(offset + 1);
+
+        Some(Capability {
+            id: read(offset),
+            offset,
+        })
+    }
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        let address = self.address;
+        self.advance(|offset| address.read_u8(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Walk the capability list in `config`, starting at `first`, returning the IDs and offsets.
+    fn walk(config: &[u8; 256], first: u8) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let mut capabilities = Capabilities {
+            address: PciAddress::new(0, 0, 0),
+            offset: first,
+            remaining: MAX_CAPABILITIES,
+        };
+
+        core::iter::from_fn(move || capabilities.advance(|offset| config[offset as usize]))
+            .map(|capability| (capability.id, capability.offset))
+    }
+
+    #[test]
+    fn walks_capabilities() {
+        let mut config = [0; 256];
+        config[0x40..0x42].copy_from_slice(&[0x05, 0x53]);
+        config[0x50..0x52].copy_from_slice(&[0x11, 0x00]);
+
+        // The reserved bits of the pointers are ignored.
+        let mut capabilities = walk(&config, 0x42);
+        assert_eq!(capabilities.next(), Some((0x05, 0x40)));
+        assert_eq!(capabilities.next(), Some((0x11, 0x50)));
+        assert_eq!(capabilities.next(), None);
+
+        assert_eq!(walk(&config, 0).count(), 0);
+    }
+
+    #[test]
+    fn stops_at_looping_capabilities() {
+        let mut config = [0; 256];
+        config[0x40..0x42].copy_from_slice(&[0x09, 0x40]);
+
+        assert_eq!(walk(&config, 0x40).count(), MAX_CAPABILITIES);
+    }
+}
@@ -0,0 +1,439 @@
+//! Message Signaled Interrupts (MSI and MSI-X), with a fallback to the legacy INTx pin.
+//!
+//! A device signals an MSI by writing the message data to the message address, which the Local
+//! APIC designated by the address turns into an interrupt on the vector in the data. Every
+//! vector gets an IRQ line of its own from [`irq::allocate`], so unlike INTx it is never shared.
+
+use super::{PciAddress, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE, COMMAND_MEMORY_SPACE};
+use crate::{
+    apic::{self, lapic},
+    irq,
+    mem::mmio,
+};
+use x86_64::VirtAddr;
+
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_CAPABLE_SHIFT: u16 = 1;
+const MSI_CONTROL_MULTIPLE_ENABLE_SHIFT: u16 = 4;
+const MSI_CONTROL_MULTIPLE_MASK: u16 = 0b111;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+/// The most vectors MSI can signal.
+const MSI_MAX_VECTORS: usize = 32;
+
+const MSIX_CONTROL_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+const MSIX_TABLE_BIR_MASK: u32 = 0b111;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDRESS_LOW: u64 = 0;
+const MSIX_ENTRY_ADDRESS_HIGH: u64 = 4;
+const MSIX_ENTRY_DATA: u64 = 8;
+const MSIX_ENTRY_VECTOR_CONTROL: u64 = 12;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1 << 0;
+
+const MESSAGE_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MESSAGE_ADDRESS_DESTINATION_SHIFT: u32 = 12;
+
+/// The highest Local APIC ID a message address can designate.
+const MAX_DESTINATION: u32 = 0xFF;
+
+/// How the interrupts of a function are signaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqKind {
+    MsiX,
+    Msi,
+
+    /// The INTx pin, on an ISA line that may be shared with other devices.
+    Intx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function supports neither MSI nor MSI-X, and its INTx pin is not routed.
+    NoInterrupt,
+
+    /// There are not enough free lines for the minimum amount of vectors requested.
+    NoVectors,
+
+    /// The Local APIC ID cannot be put in a message address.
+    UnreachableApic,
+}
+
+/// The interrupt vectors allocated to a function by [`PciAddress::alloc_irq_vectors`].
+#[derive(Debug)]
+pub struct IrqVectors {
+    address: PciAddress,
+    kind: IrqKind,
+    first_line: u8,
+    count: usize,
+
+    /// The offset of the MSI or MSI-X capability.
+    capability: u16,
+
+    /// The MSI-X table, mapped uncached.
+    table: Option<VirtAddr>,
+}
+
+impl IrqVectors {
+    pub fn kind(&self) -> IrqKind {
+        self.kind
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Return the IRQ line vector `index` is delivered on, to register its handler on.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `index` is out of range.
+    pub fn line(&self, index: usize) -> u8 {
+        assert!(index < self.count, "pci: no vector {index}");
+        self.first_line + index as u8
+    }
+
+    /// Deliver every vector to the Local APIC whose ID is `apic_id`.
+    pub fn set_target(&self, apic_id: u32) -> Result<(), MsiError> {
+        let address = message_address(apic_id)?;
+
+        match self.kind {
+            IrqKind::MsiX => {
+                for index in 0..self.count {
+                    self.write_msix_entry(index, address, self.data(index));
+                }
+            }
+            IrqKind::Msi => self.write_msi_address(address),
+            // INTx is routed by the interrupt controller.
+            IrqKind::Intx => {}
+        }
+
+        Ok(())
+    }
+
+    /// Disable the vectors and release their lines.
+    pub fn free(self) {
+        let control = self.capability + 2;
+
+        match self.kind {
+            IrqKind::MsiX => {
+                for index in 0..self.count {
+                    self.mask_msix_entry(index);
+                }
+
+                let msix_control = self.address.read_u16(control);
+                self.address
+                    .write_u16(control, msix_control & !MSIX_CONTROL_ENABLE);
+
+                if let Some(table) = self.table {
+                    mmio::unmap(table, msix_table_len(msix_control));
+                }
+            }
+            IrqKind::Msi => {
+                let msi_control = self.address.read_u16(control);
+                self.address
+                    .write_u16(control, msi_control & !MSI_CONTROL_ENABLE);
+            }
+            IrqKind::Intx => return,
+        }
+
+        irq::free(self.first_line, self.allocated());
+        self.address.update_command(0, COMMAND_INTX_DISABLE);
+    }
+
+    /// Return the amount of lines allocated, which MSI rounds up to a power of two.
+    fn allocated(&self) -> usize {
+        match self.kind {
+            IrqKind::Msi => self.count.next_power_of_two(),
+            _ => self.count,
+        }
+    }
+
+    /// Return the message data for vector `index`: fixed delivery, edge triggered.
+    fn data(&self, index: usize) -> u32 {
+        irq::vector(self.line(index)) as u32
+    }
+
+    fn msix_entry(&self, index: usize) -> *mut u32 {
+        let table = self.table.expect("pci: MSI-X table not mapped");
+        (table + index as u64 * MSIX_ENTRY_SIZE).as_mut_ptr()
+    }
+
+    fn mask_msix_entry(&self, index: usize) {
+        let entry = self.msix_entry(index);
+
+        // SAFETY: `entry` points to a mapped MSI-X table entry.
+        unsafe {
+            let control = entry.add(MSIX_ENTRY_VECTOR_CONTROL as usize / 4);
+            control.write_volatile(control.read_volatile() | MSIX_VECTOR_CONTROL_MASKED);
+        }
+    }
+
+    /// Program MSI-X table entry `index`, masking it while it is inconsistent.
+    fn write_msix_entry(&self, index: usize, address: u32, data: u32) {
+        let entry = self.msix_entry(index);
+        self.mask_msix_entry(index);
+
+        // SAFETY: as above.
+        unsafe {
+            entry
+                .add(MSIX_ENTRY_ADDRESS_LOW as usize / 4)
+                .write_volatile(address);
+            entry
+                .add(MSIX_ENTRY_ADDRESS_HIGH as usize / 4)
+                .write_volatile(0);
+            entry.add(MSIX_ENTRY_DATA as usize / 4).write_volatile(data);
+
+            let control = entry.add(MSIX_ENTRY_VECTOR_CONTROL as usize / 4);
+            control.write_volatile(control.read_volatile() & !MSIX_VECTOR_CONTROL_MASKED);
+        }
+    }
+
+    fn write_msi_address(&self, address: u32) {
+        let control = self.address.read_u16(self.capability + 2);
+
+        self.address.write_u32(self.capability + 4, address);
+        if control & MSI_CONTROL_64BIT != 0 {
+            self.address.write_u32(self.capability + 8, 0);
+        }
+    }
+}
+
+/// Return the size in bytes of the MSI-X table described by the control register `control`.
+fn msix_table_len(control: u16) -> usize {
+    ((control & MSIX_CONTROL_TABLE_SIZE_MASK) as usize + 1) * MSIX_ENTRY_SIZE as usize
+}
+
+/// Return the message address designating the Local APIC whose ID is `apic_id`.
+fn message_address(apic_id: u32) -> Result<u32, MsiError> {
+    if apic_id > MAX_DESTINATION {
+        return Err(MsiError::UnreachableApic);
+    }
+
+    Ok(MESSAGE_ADDRESS_BASE | apic_id << MESSAGE_ADDRESS_DESTINATION_SHIFT)
+}
+
+impl PciAddress {
+    /// Allocate between `min` and `max` interrupt vectors, preferring MSI-X, then MSI, and
+    /// falling back to a single INTx line if `min` is 1. The vectors are delivered to the
+    /// current CPU until retargeted with [`IrqVectors::set_target`].
+    ///
+    /// The handlers of the lines must be registered with [`irq::register`], before the device is
+    /// told to raise interrupts.
+    pub fn alloc_irq_vectors(&self, min: usize, max: usize) -> Result<IrqVectors, MsiError> {
+        assert!(
+            min >= 1 && min <= max,
+            "pci: invalid vector range {min}..={max}"
+        );
+
+        // Messages are delivered to Local APICs, so the 8259 PIC only has INTx.
+        if apic::is_enabled() {
+            let address = message_address(lapic::get().id())?;
+
+            if let Some(capability) = self.find_capability(CAP_MSIX) {
+                if let Some(vectors) = self.enable_msix(capability, min, max, address) {
+                    return Ok(vectors);
+                }
+            }
+
+            if let Some(capability) = self.find_capability(CAP_MSI) {
+                if let Some(vectors) = self.enable_msi(capability, min, max, address) {
+                    return Ok(vectors);
+                }
+            }
+        }
+
+        if min > 1 {
+            return Err(MsiError::NoVectors);
+        }
+
+        let line = self.interrupt_line().ok_or(MsiError::NoInterrupt)?;
+        self.update_command(0, COMMAND_INTX_DISABLE);
+
+        Ok(IrqVectors {
+            address: *self,
+            kind: IrqKind::Intx,
+            first_line: line,
+            count: 1,
+            capability: 0,
+            table: None,
+        })
+    }
+
+    fn enable_msix(
+        &self,
+        capability: u16,
+        min: usize,
+        max: usize,
+        address: u32,
+    ) -> Option<IrqVectors> {
+        let control = self.read_u16(capability + 2);
+        let table_size = (control & MSIX_CONTROL_TABLE_SIZE_MASK) as usize + 1;
+        let table = self.read_u32(capability + 4);
+
+        // The table may be above 4 GiB, out of reach of the HHDM, and must not be cached anyway.
+        let bar = self.memory_bar((table & MSIX_TABLE_BIR_MASK) as u8)?;
+        let table_len = msix_table_len(control);
+        let table = mmio::map(bar + (table & !MSIX_TABLE_BIR_MASK) as u64, table_len)?;
+
+        let Some((first_line, count)) = allocate_lines(min, max.min(table_size), 1) else {
+            mmio::unmap(table, table_len);
+            return None;
+        };
+
+        self.update_command(COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER, 0);
+
+        // Mask the whole function while the table is being programmed.
+        self.write_u16(
+            capability + 2,
+            control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK,
+        );
+
+        let vectors = IrqVectors {
+            address: *self,
+            kind: IrqKind::MsiX,
+            first_line,
+            count,
+            capability,
+            table: Some(table),
+        };
+
+        for index in 0..table_size {
+            if index < count {
+                vectors.write_msix_entry(index, address, vectors.data(index));
+            } else {
+                vectors.mask_msix_entry(index);
+            }
+        }
+
+        self.write_u16(capability + 2, control | MSIX_CONTROL_ENABLE);
+        self.update_command(COMMAND_INTX_DISABLE, 0);
+
+        Some(vectors)
+    }
+
+    fn enable_msi(
+        &self,
+        capability: u16,
+        min: usize,
+        max: usize,
+        address: u32,
+    ) -> Option<IrqVectors> {
+        let control = self.read_u16(capability + 2);
+        let capable =
+            1 << ((control >> MSI_CONTROL_MULTIPLE_CAPABLE_SHIFT) & MSI_CONTROL_MULTIPLE_MASK);
+
+        let (first_line, count) = allocate_msi_block(min, max.min(capable))?;
+
+        let vectors = IrqVectors {
+            address: *self,
+            kind: IrqKind::Msi,
+            first_line,
+            count,
+            capability,
+            table: None,
+        };
+
+        let is_64bit = control & MSI_CONTROL_64BIT != 0;
+        let data = if is_64bit {
+            capability + 0xC
+        } else {
+            capability + 8
+        };
+
+        vectors.write_msi_address(address);
+        self.write_u16(data, vectors.data(0) as u16);
+
+        if control & MSI_CONTROL_PER_VECTOR_MASKING != 0 {
+            let mask = if is_64bit {
+                capability + 0x10
+            } else {
+                capability + 0xC
+            };
+
+            self.write_u32(mask, 0);
+        }
+
+        let multiple_enable = vectors.allocated().trailing_zeros() as u16;
+        let control = control & !(MSI_CONTROL_MULTIPLE_MASK << MSI_CONTROL_MULTIPLE_ENABLE_SHIFT)
+            | multiple_enable << MSI_CONTROL_MULTIPLE_ENABLE_SHIFT
+            | MSI_CONTROL_ENABLE;
+
+        self.update_command(COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE, 0);
+        self.write_u16(capability + 2, control);
+
+        Some(vectors)
+    }
+}
+
+/// Allocate lines for as many MSI vectors as possible between `min` and `max`, returning the
+/// first line and the amount of vectors.
+///
+/// The device signals vectors by setting the low bits of the data, so lines are allocated in a
+/// power of two block aligned to its size, which may be larger than the amount of vectors.
+fn allocate_msi_block(min: usize, max: usize) -> Option<(u8, usize)> {
+    (min..=max.min(MSI_MAX_VECTORS)).rev().find_map(|count| {
+        let block = count.next_power_of_two();
+        Some((irq::allocate(block, block)?, count))
+    })
+}
+
+/// Allocate as many lines as possible between `min` and `max`, returning the first one and the
+/// amount allocated.
+fn allocate_lines(min: usize, max: usize, align: usize) -> Option<(u8, usize)> {
+    (min..=max)
+        .rev()
+        .find_map(|count| Some((irq::allocate(count, align)?, count)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_message_addresses() {
+        assert_eq!(message_address(0), Ok(0xFEE0_0000));
+        assert_eq!(message_address(3), Ok(0xFEE0_3000));
+        assert_eq!(message_address(MAX_DESTINATION), Ok(0xFEEF_F000));
+        assert_eq!(
+            message_address(MAX_DESTINATION + 1),
+            Err(MsiError::UnreachableApic)
+        );
+    }
+
+    #[test]
+    fn allocates_aligned_msi_blocks() {
+        let (first, count) = allocate_msi_block(1, 3).expect("no lines left");
+        assert_eq!(count, 3);
+        assert_eq!(first % 4, 0);
+
+        let vectors = IrqVectors {
+            address: PciAddress::new(0, 0, 0),
+            kind: IrqKind::Msi,
+            first_line: first,
+            count,
+            capability: 0,
+            table: None,
+        };
+        assert_eq!(vectors.allocated(), 4);
+        irq::free(first, vectors.allocated());
+
+        let (first, count) = allocate_msi_block(1, 2 * MSI_MAX_VECTORS).expect("no lines left");
+        assert!(count <= MSI_MAX_VECTORS);
+        assert_eq!(first as usize % count.next_power_of_two(), 0);
+        irq::free(first, count.next_power_of_two());
+    }
+
+    #[test]
+    fn sizes_msix_tables() {
+        assert_eq!(msix_table_len(0), 16);
+        assert_eq!(msix_table_len(MSIX_CONTROL_ENABLE | 7), 128);
+        assert_eq!(msix_table_len(MSIX_CONTROL_TABLE_SIZE_MASK), 2048 * 16);
+    }
+}
@@ -0,0 +1,139 @@
+//! Intel 8253/8254 Programmable Interval Timer (PIT) driver.
+
+use crate::sync::IrqSpinLock;
+use x86_64::instructions::port::Port;
+
+/// The frequency of the PIT input clock in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+
+/// Controls the gate of channel 2 and exposes its output.
+const CHANNEL2_GATE: u16 = 0x61;
+
+const GATE_ENABLE: u8 = 1 << 0;
+const GATE_SPEAKER: u8 = 1 << 1;
+const GATE_OUTPUT: u8 = 1 << 5;
+
+const COMMAND_CHANNEL0: u8 = 0b00 << 6;
+const COMMAND_CHANNEL2: u8 = 0b10 << 6;
+const COMMAND_ACCESS_LOHI: u8 = 0b11 << 4;
+const COMMAND_MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0b000 << 1;
+const COMMAND_MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+/// The largest reload value, which is written as 0.
+const MAX_COUNT: u64 = 0x10000;
+
+struct Pit {
+    channel0: Port<u8>,
+    channel2: Port<u8>,
+    command: Port<u8>,
+    gate: Port<u8>,
+}
+
+impl Pit {
+    /// Load `count` into a channel, which must have been selected with lobyte/hibyte access.
+    fn load(channel: &mut Port<u8>, count: u64) {
+        let count = count.clamp(1, MAX_COUNT) as u16;
+
+        // SAFETY: `channel` is one of the PIT data ports. A count of 0 is interpreted as
+        // `MAX_COUNT`, which the truncation above produces.
+        unsafe {
+            channel.write(count as u8);
+            channel.write((count >> 8) as u8);
+        }
+    }
+}
+
+static PIT: IrqSpinLock<Pit> = IrqSpinLock::new(Pit {
+    channel0: Port::new(CHANNEL0),
+    channel2: Port::new(CHANNEL2),
+    command: Port::new(COMMAND),
+    gate: Port::new(CHANNEL2_GATE),
+});
+
+/// Convert a duration in nanoseconds to PIT ticks, rounding up.
+fn ns_to_count(ns: u64) -> u64 {
+    (ns as u128 * FREQUENCY as u128).div_ceil(1_000_000_000) as u64
+}
+
+/// Make channel 0 raise IRQ0 `hz` times per second. Returns the frequency actually programmed,
+/// which differs from `hz` by the rounding of the divisor.
+pub fn set_periodic(hz: u64) -> u64 {
+    // A divisor of 1 is not allowed in rate generator mode.
+    let divisor = (FREQUENCY / hz).clamp(2, MAX_COUNT);
+    let pit = &mut *PIT.lock();
+
+    // SAFETY: these are the standard PIT ports.
+    unsafe {
+        pit.command
+            .write(COMMAND_CHANNEL0 | COMMAND_ACCESS_LOHI | COMMAND_MODE_RATE_GENERATOR);
+    }
+
+    Pit::load(&mut pit.channel0, divisor);
+
+    FREQUENCY / divisor
+}
+
+/// Make channel 0 raise IRQ0 once, `ns` nanoseconds from now. Durations longer than the counter
+/// allows, about 55 ms, are truncated.
+pub fn set_oneshot(ns: u64) {
+    let pit = &mut *PIT.lock();
+
+    // SAFETY: these are the standard PIT ports.
+    unsafe {
+        pit.command.write(
+            COMMAND_CHANNEL0 | COMMAND_ACCESS_LOHI | COMMAND_MODE_INTERRUPT_ON_TERMINAL_COUNT,
+        );
+    }
+
+    Pit::load(&mut pit.channel0, ns_to_count(ns));
+}
+
+/// Stop channel 0 from raising further interrupts.
+///
+/// The PIT cannot be disabled, so the channel is left waiting for a count that is never loaded.
+pub fn stop() {
+    let pit = &mut *PIT.lock();
+
+    // SAFETY: these are the standard PIT ports.
+    unsafe {
+        pit.command.write(
+            COMMAND_CHANNEL0 | COMMAND_ACCESS_LOHI | COMMAND_MODE_INTERRUPT_ON_TERMINAL_COUNT,
+        );
+    }
+}
+
+/// Busy-wait for at least `ns` nanoseconds by polling channel 2, which needs neither interrupts
+/// nor any other timer to be set up.
+pub fn wait(ns: u64) {
+    let pit = &mut *PIT.lock();
+    let mut remaining = ns_to_count(ns);
+
+    // SAFETY: these are the standard PIT ports.
+    unsafe {
+        // Enable the gate of channel 2 without driving the speaker.
+        let gate = (pit.gate.read() & !GATE_SPEAKER) | GATE_ENABLE;
+        pit.gate.write(gate);
+
+        while remaining > 0 {
+            let count = remaining.min(MAX_COUNT);
+
+            // The output goes low when the count is loaded and high once it reaches zero.
+            pit.command.write(
+                COMMAND_CHANNEL2 | COMMAND_ACCESS_LOHI | COMMAND_MODE_INTERRUPT_ON_TERMINAL_COUNT,
+            );
+            Pit::load(&mut pit.channel2, count);
+
+            while pit.gate.read() & GATE_OUTPUT == 0 {
+                core::hint::spin_loop();
+            }
+
+            remaining -= count;
+        }
+
+        pit.gate.write(gate & !GATE_ENABLE);
+    }
+}
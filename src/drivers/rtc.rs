@@ -0,0 +1,342 @@
+//! CMOS Real-Time Clock (RTC) driver.
+
+use crate::{
+    idt::TrapFrame,
+    irq::{self, IrqReturn},
+    sync::IrqSpinLock,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+use x86_64::instructions::port::Port;
+
+/// The ISA IRQ the RTC raises its periodic interrupt on.
+pub const IRQ: u8 = 8;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+/// Set in the hours register for PM times in 12-hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// The frequency of the RTC time base in Hz.
+const BASE_FREQUENCY: u32 = 32768;
+
+/// Used when the FADT does not describe a century register.
+const DEFAULT_CENTURY: u16 = 20;
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        // SAFETY: these are the standard CMOS ports. Bit 7 of the index is left clear to keep
+        // NMIs enabled.
+        unsafe {
+            self.index.write(reg);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        // SAFETY: as above.
+        unsafe {
+            self.index.write(reg);
+            self.data.write(value);
+        }
+    }
+}
+
+static CMOS: IrqSpinLock<Cmos> = IrqSpinLock::new(Cmos {
+    index: Port::new(CMOS_INDEX),
+    data: Port::new(CMOS_DATA),
+});
+
+/// The CMOS register holding the century, from the FADT, or 0 if there is none.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// The amount of periodic interrupts received.
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time of day in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Return the amount of seconds since the Unix epoch.
+    pub fn to_unix(self) -> u64 {
+        // Count years from March so that the leap day falls at the end of the year.
+        let (year, month) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            _ => (self.year as i64, self.month as i64 - 3),
+        };
+
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        // 719468 is the amount of days from 0000-03-01 to 1970-01-01.
+        let days = era * 146097 + day_of_era - 719468;
+
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The raw values of the time registers.
+#[derive(PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl Registers {
+    fn read(cmos: &mut Cmos) -> Self {
+        while cmos.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+
+        let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+
+        Self {
+            second: cmos.read(REG_SECONDS),
+            minute: cmos.read(REG_MINUTES),
+            hour: cmos.read(REG_HOURS),
+            day: cmos.read(REG_DAY),
+            month: cmos.read(REG_MONTH),
+            year: cmos.read(REG_YEAR),
+            century: match century_register {
+                0 => 0,
+                reg => cmos.read(reg),
+            },
+        }
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Decode the value of a time register in the format selected by status register B.
+fn decode(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 {
+        value
+    } else {
+        bcd_to_binary(value)
+    }
+}
+
+/// Decode the value of the hours register, converting 12-hour times to 24-hour ones.
+fn decode_hour(value: u8, status_b: u8) -> u8 {
+    let pm = value & HOURS_PM != 0;
+    let mut hour = decode(value & !HOURS_PM, status_b);
+
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    hour
+}
+
+/// Read the current date and time from the RTC.
+pub fn read() -> DateTime {
+    let (registers, status_b) = {
+        let cmos = &mut *CMOS.lock();
+
+        // An update may begin right after the update-in-progress flag is checked, so read until
+        // two consecutive reads agree.
+        let mut registers = Registers::read(cmos);
+        loop {
+            let again = Registers::read(cmos);
+            if again == registers {
+                break;
+            }
+
+            registers = again;
+        }
+
+        (registers, cmos.read(REG_STATUS_B))
+    };
+
+    let decode = |value: u8| decode(value, status_b);
+
+    let century = match registers.century {
+        0 => DEFAULT_CENTURY,
+        century => decode(century) as u16,
+    };
+
+    DateTime {
+        year: century * 100 + decode(registers.year) as u16,
+        month: decode(registers.month),
+        day: decode(registers.day),
+        hour: decode_hour(registers.hour, status_b),
+        minute: decode(registers.minute),
+        second: decode(registers.second),
+    }
+}
+
+/// Enable the periodic interrupt on IRQ8 at `32768 >> (rate - 1)` Hz, where `rate` is between 3
+/// (8192 Hz) and 15 (2 Hz). Returns the frequency programmed.
+///
+/// # Panics
+///
+/// This function will panic if `rate` is out of range.
+pub fn enable_periodic(rate: u8) -> u32 {
+    assert!((3..=15).contains(&rate), "rtc: invalid rate {rate}");
+
+    let cmos = &mut *CMOS.lock();
+
+    let status_a = cmos.read(REG_STATUS_A);
+    cmos.write(REG_STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+
+    let status_b = cmos.read(REG_STATUS_B);
+    cmos.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+
+    // Clear any interrupt already pending so that the next one is raised.
+    cmos.read(REG_STATUS_C);
+
+    BASE_FREQUENCY >> (rate - 1)
+}
+
+/// Disable the periodic interrupt.
+pub fn disable_periodic() {
+    let cmos = &mut *CMOS.lock();
+
+    let status_b = cmos.read(REG_STATUS_B);
+    cmos.write(REG_STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+}
+
+/// Return the amount of periodic interrupts received.
+pub fn periodic_interrupts() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Handle an interrupt from the RTC.
+fn interrupt(_: &mut TrapFrame) -> IrqReturn {
+    // The RTC raises no further interrupts until status register C is read.
+    let status_c = CMOS.lock().read(REG_STATUS_C);
+
+    if status_c & STATUS_C_PERIODIC_INTERRUPT != 0 {
+        PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Any flag set in status register C means the RTC raised the interrupt.
+    if status_c != 0 {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
+}
+
+/// Initialize the RTC, using the CMOS register `century_register` from the FADT for the century
+/// if it is given.
+pub fn init(century_register: Option<u8>) {
+    CENTURY_REGISTER.store(century_register.unwrap_or(0), Ordering::Relaxed);
+
+    irq::register(IRQ, interrupt, "rtc").expect("rtc: unable to register IRQ handler");
+
+    log::info!("RTC reports {}", read());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn converts_to_unix_time() {
+        assert_eq!(date_time(1970, 1, 1, 0, 0, 0).to_unix(), 0);
+        assert_eq!(date_time(2000, 2, 29, 0, 0, 0).to_unix(), 951782400);
+        assert_eq!(date_time(2024, 2, 29, 12, 34, 56).to_unix(), 1709210096);
+        assert_eq!(date_time(2100, 3, 1, 0, 0, 0).to_unix(), 4107542400);
+
+        // Past the end of signed and unsigned 32-bit time.
+        assert_eq!(date_time(2038, 1, 19, 3, 14, 8).to_unix(), 1 << 31);
+        assert_eq!(date_time(2106, 2, 7, 6, 28, 16).to_unix(), 1 << 32);
+    }
+
+    #[test]
+    fn decodes_bcd() {
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(bcd_to_binary(0x09), 9);
+        assert_eq!(bcd_to_binary(0x10), 10);
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(bcd_to_binary(0x99), 99);
+
+        assert_eq!(decode(0x42, 0), 42);
+        assert_eq!(decode(0x42, STATUS_B_BINARY), 0x42);
+    }
+
+    #[test]
+    fn converts_12_hour_times() {
+        const BCD_24_HOUR: u8 = STATUS_B_24_HOUR;
+        const BINARY_12_HOUR: u8 = STATUS_B_BINARY;
+
+        assert_eq!(decode_hour(0x12, 0), 0);
+        assert_eq!(decode_hour(0x01, 0), 1);
+        assert_eq!(decode_hour(0x11, 0), 11);
+        assert_eq!(decode_hour(HOURS_PM | 0x12, 0), 12);
+        assert_eq!(decode_hour(HOURS_PM | 0x01, 0), 13);
+        assert_eq!(decode_hour(HOURS_PM | 0x11, 0), 23);
+
+        assert_eq!(decode_hour(HOURS_PM | 11, BINARY_12_HOUR), 23);
+        assert_eq!(decode_hour(0x23, BCD_24_HOUR), 23);
+        assert_eq!(decode_hour(0x00, BCD_24_HOUR), 0);
+    }
+}
@@ -1,12 +1,18 @@
+use crate::{logger, sync::IrqSpinLock};
 use core::fmt::{Arguments, Write};
-use spin::{Mutex, Once};
+use spin::Once;
 use uart_16550::SerialPort;
 
-pub static SERIAL1: Once<Mutex<SerialPort>> = Once::new();
+pub static SERIAL1: Once<IrqSpinLock<SerialPort>> = Once::new();
 
 #[doc(hidden)]
 pub fn _print(args: Arguments<'_>) {
-    SERIAL1.get().unwrap().lock().write_fmt(args);
+    let serial = SERIAL1.get().unwrap();
+
+    match logger::bust_spins() {
+        Some(spins) => serial.lock_or_bust(spins).write_fmt(args),
+        None => serial.lock().write_fmt(args),
+    };
 }
 
 pub macro serial_print($($arg:tt)*) {
@@ -24,5 +30,5 @@ pub fn init() {
     let mut sp = unsafe { SerialPort::new(0x3F8) };
     sp.init();
 
-    SERIAL1.call_once(|| Mutex::new(sp));
+    SERIAL1.call_once(|| IrqSpinLock::new(sp));
 }
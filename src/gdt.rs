@@ -1,43 +1,141 @@
-use spin::Lazy;
+use crate::{percpu, smp::MAX_CPUS};
+use core::cell::UnsafeCell;
+use spin::Once;
 use x86_64::{
+    instructions::tables::load_tss,
     registers::segmentation::{Segment, CS, SS},
-    structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
 };
 
-struct SegmentSelectors {
-    kcode: SegmentSelector,
-    kdata: SegmentSelector,
-    ucode: SegmentSelector,
-    udata: SegmentSelector,
+/// IST slot used by the double fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// IST slot used by the non-maskable interrupt handler.
+pub const NMI_IST_INDEX: u16 = 1;
+
+/// IST slot used by the machine check handler.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_STACK_COUNT: usize = 3;
+const IST_STACK_SIZE: usize = 5 * 4096; // 20 KiB
+
+/// The size of the stack the CPU switches to when entering the kernel from user mode.
+const KERNEL_STACK_SIZE: usize = 4 * 4096; // 16 KiB
+
+/// A stack the CPU switches to by itself, through the TSS or on system calls.
+#[repr(C, align(16))]
+struct Stack<const SIZE: usize>(UnsafeCell<[u8; SIZE]>);
+
+// SAFETY: these stacks are only ever written to by the CPU they belong to, after switching to
+// them on kernel entry.
+unsafe impl<const SIZE: usize> Sync for Stack<SIZE> {}
+
+impl<const SIZE: usize> Stack<SIZE> {
+    const fn new() -> Self {
+        Self(UnsafeCell::new([0; SIZE]))
+    }
+
+    /// Return the address of the top of the stack, as stacks grow downwards.
+    fn top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.0.get()) + SIZE
+    }
+}
+
+/// The segments of the GDT, in an order compatible with `SYSCALL` and `SYSRET`: the kernel data
+/// segment follows the kernel code segment, and the user data segment precedes the user code
+/// segment.
+pub(crate) struct SegmentSelectors {
+    pub kcode: SegmentSelector,
+    pub kdata: SegmentSelector,
+    pub udata: SegmentSelector,
+    pub ucode: SegmentSelector,
+    pub tss: SegmentSelector,
 }
 
-static GDT: Lazy<(GlobalDescriptorTable, SegmentSelectors)> = Lazy::new(|| {
-    let mut gdt = GlobalDescriptorTable::new();
-
-    let kcode = gdt.add_entry(Descriptor::kernel_code_segment());
-    let kdata = gdt.add_entry(Descriptor::kernel_data_segment());
-    let ucode = gdt.add_entry(Descriptor::user_code_segment());
-    let udata = gdt.add_entry(Descriptor::user_data_segment());
-
-    (
-        gdt,
-        SegmentSelectors {
-            kcode,
-            kdata,
-            ucode,
-            udata,
-        },
-    )
-});
-
-/// Initialize the GDT.
+/// The GDT and TSS of a CPU, along with the stacks its TSS points to.
+struct CpuTables {
+    ist_stacks: [Stack<IST_STACK_SIZE>; IST_STACK_COUNT],
+    kernel_stack: Stack<KERNEL_STACK_SIZE>,
+    tss: Once<TaskStateSegment>,
+    gdt: Once<(GlobalDescriptorTable, SegmentSelectors)>,
+}
+
+impl CpuTables {
+    const fn new() -> Self {
+        Self {
+            ist_stacks: [Stack::new(), Stack::new(), Stack::new()],
+            kernel_stack: Stack::new(),
+            tss: Once::new(),
+            gdt: Once::new(),
+        }
+    }
+}
+
+// Only used to initialize `TABLES`.
+#[allow(clippy::declare_interior_mutable_const, clippy::large_const_arrays)]
+const CPU_TABLES: CpuTables = CpuTables::new();
+
+static TABLES: [CpuTables; MAX_CPUS] = [CPU_TABLES; MAX_CPUS];
+
+/// Initialize the GDT and TSS of the current CPU.
 pub fn init() {
-    GDT.0.load();
-    log::info!("loaded GDT");
+    let cpu = percpu::current_cpu();
+    let tables = &TABLES[cpu];
+
+    let tss = tables.tss.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+
+        for (i, stack) in tables.ist_stacks.iter().enumerate() {
+            tss.interrupt_stack_table[i] = stack.top();
+        }
+
+        tss.privilege_stack_table[0] = tables.kernel_stack.top();
+
+        tss
+    });
+
+    let (gdt, selectors) = tables.gdt.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
 
-    // Reload segment registers.
+        let kcode = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kdata = gdt.add_entry(Descriptor::kernel_data_segment());
+        let udata = gdt.add_entry(Descriptor::user_data_segment());
+        let ucode = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+
+        (
+            gdt,
+            SegmentSelectors {
+                kcode,
+                kdata,
+                udata,
+                ucode,
+                tss,
+            },
+        )
+    });
+
+    gdt.load();
+
+    // Reload segment registers and load the TSS.
     unsafe {
-        CS::set_reg(GDT.1.kcode);
-        SS::set_reg(GDT.1.kdata);
+        CS::set_reg(selectors.kcode);
+        SS::set_reg(selectors.kdata);
+        load_tss(selectors.tss);
     }
+
+    percpu::set_kernel_stack(tables.kernel_stack.top());
+
+    log::info!(
+        "loaded GDT and TSS with {IST_STACK_COUNT} IST stack(s) of {IST_STACK_SIZE} bytes on CPU {cpu}"
+    );
+}
+
+/// Return the segment selectors, which are the same on every CPU.
+pub(crate) fn selectors() -> &'static SegmentSelectors {
+    &TABLES[0].gdt.get().expect("GDT not initialized").1
 }
@@ -1,33 +1,92 @@
 mod handlers;
+mod trap;
 
+use crate::{apic, gdt, irq, sync::IrqSpinLock, syscall};
 use complete_pic::pic8259::ChainedPics;
 use handlers::*;
-use spin::{Lazy, Mutex};
-use x86_64::{instructions::interrupts, structures::idt::InterruptDescriptorTable};
+use spin::Lazy;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::idt::{Entry, EntryOptions, InterruptDescriptorTable},
+    PrivilegeLevel, VirtAddr,
+};
+
+pub use trap::TrapFrame;
 
 macro exception_handlers($idt:expr, $($exception:ident)+) {
     let idt = &mut $idt;
     $(
-        idt.$exception.set_handler_fn($exception);
+        // SAFETY: every trampoline in `trap` is a valid interrupt entry point.
+        unsafe {
+            set_trampoline(&mut idt.$exception, trap::$exception);
+        }
     )+
 }
 
+/// Point an IDT entry at one of the trampolines defined in `trap`.
+///
+/// # Safety
+///
+/// `trampoline` must be a valid interrupt entry point for the vector of `entry`.
+unsafe fn set_trampoline<F>(
+    entry: &mut Entry<F>,
+    trampoline: unsafe extern "C" fn() -> !,
+) -> &mut EntryOptions {
+    unsafe { entry.set_handler_addr(VirtAddr::new(trampoline as usize as u64)) }
+}
+
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
 
     exception_handlers! {
         idt,
-        divide_error debug non_maskable_interrupt breakpoint overflow bound_range_exceeded invalid_opcode device_not_available invalid_tss segment_not_present stack_segment_fault general_protection_fault alignment_check double_fault page_fault
+        divide_error debug breakpoint overflow bound_range_exceeded invalid_opcode device_not_available invalid_tss segment_not_present stack_segment_fault general_protection_fault page_fault x87_floating_point alignment_check simd_floating_point virtualization cp_protection_exception hv_injection_exception vmm_communication_exception security_exception
+    }
+
+    // SAFETY: the trampolines are valid interrupt entry points and the IST indices refer to
+    // valid stacks set up in the TSS by `gdt::init`.
+    unsafe {
+        set_trampoline(&mut idt.double_fault, trap::double_fault)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        set_trampoline(
+            &mut idt.non_maskable_interrupt,
+            trap::non_maskable_interrupt,
+        )
+        .set_stack_index(gdt::NMI_IST_INDEX);
+        set_trampoline(&mut idt.machine_check, trap::machine_check)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
     }
 
-    idt[PIC1_OFFSET as usize].set_handler_fn(timer);
+    for vector in irq::FIRST_VECTOR..=u8::MAX {
+        // SAFETY: the IRQ stubs are valid interrupt entry points.
+        let options = unsafe { idt[vector as usize].set_handler_addr(trap::irq_stub(vector)) };
+
+        // User mode may raise the system call vector itself.
+        if vector == syscall::VECTOR {
+            options.set_privilege_level(PrivilegeLevel::Ring3);
+        }
+    }
 
     idt
 });
 
-const PIC1_OFFSET: u8 = 32;
-const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
-static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET) });
+pub(crate) const PIC1_OFFSET: u8 = 32;
+pub(crate) const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+
+/// The line of the master PIC the slave PIC is connected to.
+const PIC_CASCADE_IRQ: u8 = 2;
+
+/// The line of each PIC its spurious interrupts are raised on.
+const PIC_SPURIOUS_IRQ: u8 = 7;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC2_COMMAND: u16 = 0xA0;
+
+/// The OCW3 command selecting the In-Service Register for the next read of the command port.
+const OCW3_READ_ISR: u8 = 0x0B;
+
+static PICS: IrqSpinLock<ChainedPics> =
+    IrqSpinLock::new(unsafe { ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET) });
 
 pub fn hlt() -> ! {
     loop {
@@ -35,6 +94,83 @@ pub fn hlt() -> ! {
     }
 }
 
+/// Signal the end of the interrupt `vector` to whichever interrupt controller is in use.
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::lapic::get().eoi();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
+/// Check if `vector` is a spurious interrupt from the 8259 PIC, which raises its lowest
+/// priority line when the line that asked for the interrupt drops before it is acknowledged.
+/// Such an interrupt is not in service, so it must not be acknowledged; the master PIC does
+/// expect the end of a spurious interrupt from the slave, which it saw on the cascade line, so
+/// that end is signalled here.
+pub(crate) fn is_spurious_pic_irq(vector: u8) -> bool {
+    let command = match vector {
+        v if v == PIC1_OFFSET + PIC_SPURIOUS_IRQ => PIC1_COMMAND,
+        v if v == PIC2_OFFSET + PIC_SPURIOUS_IRQ => PIC2_COMMAND,
+        _ => return false,
+    };
+
+    let mut pics = PICS.lock();
+    let mut port = Port::<u8>::new(command);
+
+    // SAFETY: selecting and reading the In-Service Register has no side effects.
+    let in_service = unsafe {
+        port.write(OCW3_READ_ISR);
+        port.read()
+    };
+
+    if in_service & (1 << PIC_SPURIOUS_IRQ) != 0 {
+        return false;
+    }
+
+    if command == PIC2_COMMAND {
+        // SAFETY: the cascade line of the master PIC is in service.
+        unsafe { pics.notify_end_of_interrupt(PIC1_OFFSET + PIC_CASCADE_IRQ) }
+    }
+
+    true
+}
+
+/// Mask or unmask an IRQ at the 8259 PIC.
+pub(crate) fn set_pic_masked(irq: u8, masked: bool) {
+    let mut pics = PICS.lock();
+
+    // SAFETY: changing the interrupt masks cannot violate memory safety.
+    unsafe {
+        let masks = pics.read_interrupt_masks();
+        let mut masks = u16::from_le_bytes(masks);
+
+        if masked {
+            masks |= 1 << irq;
+        } else {
+            masks &= !(1 << irq);
+        }
+
+        pics.write_interrupt_masks(masks as u8, (masks >> 8) as u8);
+    }
+}
+
+/// Mask every interrupt of the 8259 PIC, as it has been superseded by the APICs.
+pub(crate) fn disable_pic() {
+    unsafe {
+        PICS.lock().disable();
+    }
+
+    log::info!("disabled 8259 PIC");
+}
+
+/// Load the IDT on an application processor, the 8259 PIC having been set up by the BSP.
+pub fn init_ap() {
+    IDT.load();
+}
+
 /// Initialize the IDT and interrupt related facilities.
 pub fn init() {
     IDT.load();
@@ -44,7 +180,9 @@ pub fn init() {
 
     unsafe {
         pics.initialize();
-        pics.unmask();
+
+        // Lines are unmasked as handlers are registered on them, except for the cascade.
+        pics.write_interrupt_masks(!(1 << PIC_CASCADE_IRQ), u8::MAX);
     }
 
     log::info!(
@@ -1,78 +1,210 @@
+use super::trap::TrapFrame;
+use crate::{
+    backtrace,
+    cpu::{self, Feature},
+    irq, mce,
+    mem::user,
+    syscall, watchdog,
+};
+use core::arch::asm;
 use x86_64::{
-    registers::control::Cr2,
-    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    registers::{control::Cr2, rflags::RFlags},
+    structures::idt::{PageFaultErrorCode, SelectorErrorCode},
 };
 
-pub(super) extern "x86-interrupt" fn divide_error(_: InterruptStackFrame) {
-    log::error!("division by zero exception");
-}
+const SYSCALL_VECTOR: u64 = syscall::VECTOR as u64;
 
-pub(super) extern "x86-interrupt" fn debug(_: InterruptStackFrame) {
-    log::error!("debug exception");
-}
+/// Dispatch an exception to its handler, or an interrupt to the handlers of its IRQ line.
+///
+/// This is called by [`super::trap`] with the state of the interrupted context.
+pub(super) extern "C" fn dispatch(frame: &mut TrapFrame) {
+    // Interrupts are counted by `irq::dispatch`, which tells spurious ones apart.
+    if frame.vector < irq::FIRST_VECTOR as u64 {
+        irq::stats::record(frame.vector as u8);
+    }
 
-pub(super) extern "x86-interrupt" fn non_maskable_interrupt(_: InterruptStackFrame) {
-    log::error!("non maskable interrupt exception");
+    match frame.vector {
+        0 => fatal(frame, "division by zero"),
+        1 => debug(frame),
+        2 => non_maskable_interrupt(frame),
+        3 => breakpoint(frame),
+        4 => fatal(frame, "overflow"),
+        5 => fatal(frame, "bound range exceeded"),
+        6 => fatal(frame, "invalid opcode"),
+        7 => fatal(frame, "device not available"),
+        8 => double_fault(frame),
+        10 => selector_fault(frame, "invalid TSS"),
+        11 => selector_fault(frame, "segment not present"),
+        12 => selector_fault(frame, "stack segment fault"),
+        13 => general_protection_fault(frame),
+        14 => page_fault(frame),
+        16 => x87_floating_point(frame),
+        17 => fatal(frame, "alignment check"),
+        18 => machine_check(frame),
+        19 => simd_floating_point(frame),
+        20 => fatal(frame, "virtualization"),
+        21 => control_protection(frame),
+        28 => fatal(frame, "hypervisor injection"),
+        29 => fatal(frame, "VMM communication"),
+        30 => security(frame),
+        SYSCALL_VECTOR => syscall::interrupt(frame),
+        32.. => irq::dispatch(frame),
+        _ => fatal(frame, "unknown"),
+    }
 }
 
-pub(super) extern "x86-interrupt" fn breakpoint(_: InterruptStackFrame) {
-    log::error!("breakpoint exception");
-}
+/// Report an exception the interrupted context cannot recover from and panic. The panic prints
+/// the backtrace of the interrupted context.
+fn fatal(frame: &TrapFrame, name: &str) -> ! {
+    log::error!(
+        "{name} exception (vector {}, error code {:#X}) in {} mode",
+        frame.vector,
+        frame.error_code,
+        if frame.is_user() { "user" } else { "kernel" }
+    );
+    frame.dump();
+    backtrace::set_fault_context(frame.rip, frame.rbp);
 
-pub(super) extern "x86-interrupt" fn overflow(_: InterruptStackFrame) {
-    log::error!("overflow exception");
+    panic!("unrecoverable {name} exception at {:#X}", frame.rip);
 }
 
-pub(super) extern "x86-interrupt" fn bound_range_exceeded(_: InterruptStackFrame) {
-    log::error!("bound range exceeded exception");
+fn debug(frame: &TrapFrame) {
+    log::debug!("debug exception at {:#X}", frame.rip);
 }
 
-pub(super) extern "x86-interrupt" fn invalid_opcode(_: InterruptStackFrame) {
-    log::error!("invalid opcode exception");
+fn non_maskable_interrupt(frame: &TrapFrame) {
+    if watchdog::nmi(frame) {
+        return;
+    }
+
+    log::error!("non maskable interrupt exception");
+    frame.dump();
+    backtrace::print_from(frame.rip, frame.rbp);
 }
 
-pub(super) extern "x86-interrupt" fn device_not_available(_: InterruptStackFrame) {
-    log::error!("device not available exception");
+fn breakpoint(frame: &TrapFrame) {
+    log::debug!("breakpoint exception at {:#X}", frame.rip);
 }
 
-pub(super) extern "x86-interrupt" fn invalid_tss(_: InterruptStackFrame, _: u64) {
-    log::error!("invalid TSS exception");
+fn double_fault(frame: &TrapFrame) -> ! {
+    fatal(frame, "double fault");
 }
 
-pub(super) extern "x86-interrupt" fn segment_not_present(_: InterruptStackFrame, _: u64) {
-    log::error!("segment not present exception");
+/// Handle an exception whose error code refers to a segment selector.
+fn selector_fault(frame: &TrapFrame, name: &str) -> ! {
+    let ec = SelectorErrorCode::new_truncate(frame.error_code);
+
+    if ec.is_null() {
+        log::error!("{name}: not caused by a segment selector");
+    } else {
+        log::error!(
+            "{name}: selector index {} in {:?} (external: {})",
+            ec.index(),
+            ec.descriptor_table(),
+            ec.external()
+        );
+    }
+
+    fatal(frame, name);
 }
 
-pub(super) extern "x86-interrupt" fn stack_segment_fault(_: InterruptStackFrame, _: u64) {
-    log::error!("stack segment fault exception");
+fn general_protection_fault(frame: &TrapFrame) -> ! {
+    if !frame.is_user() && syscall::is_bad_iret(frame.rip) {
+        syscall::report_bad_iret(frame);
+    }
+
+    selector_fault(frame, "general protection fault");
 }
 
-pub(super) extern "x86-interrupt" fn general_protection_fault(_: InterruptStackFrame, _: u64) {
-    log::error!("general protection fault exception");
+fn page_fault(frame: &mut TrapFrame) {
+    let addr = Cr2::read_raw();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+
+    // A user copy that raced with a mapping going away fails instead.
+    if !frame.is_user() {
+        if let Some(fixup) = user::fixup(frame.rip) {
+            frame.rip = fixup;
+            return;
+        }
+    }
+
+    log::error!("virtual address {addr:#X} caused a page fault ({error_code:?})");
+
+    // Protection violations by the kernel on user pages are raised by SMEP and SMAP, unless the
+    // page is read-only or not executable.
+    let kernel_on_user_page = addr < user::USER_END
+        && error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && !error_code.contains(PageFaultErrorCode::USER_MODE);
+
+    if kernel_on_user_page
+        && cpu::has(Feature::Smep)
+        && error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+    {
+        log::error!("SMEP violation: the kernel executed a user page");
+    } else if kernel_on_user_page
+        && cpu::has(Feature::Smap)
+        && !error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && frame.rflags & RFlags::ALIGNMENT_CHECK.bits() == 0
+    {
+        log::error!("SMAP violation: the kernel accessed a user page outside of a user copy");
+    }
+
+    fatal(frame, "page fault");
 }
 
-pub(super) extern "x86-interrupt" fn alignment_check(_: InterruptStackFrame, _: u64) {
-    log::error!("alignment check exception");
+fn x87_floating_point(frame: &TrapFrame) -> ! {
+    let status: u16;
+
+    // SAFETY: reading the status word has no side effects.
+    unsafe { asm!("fnstsw ax", out("ax") status, options(nomem, nostack, preserves_flags)) };
+
+    log::error!("x87 floating point status word {status:#06X}");
+    fatal(frame, "x87 floating point");
 }
 
-pub(super) extern "x86-interrupt" fn double_fault(_: InterruptStackFrame, _: u64) -> ! {
-    loop {}
+fn simd_floating_point(frame: &TrapFrame) -> ! {
+    let mut mxcsr = 0u32;
+
+    // SAFETY: `mxcsr` is valid for writes.
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags)) };
+
+    log::error!("SIMD floating point MXCSR {mxcsr:#010X}");
+    fatal(frame, "SIMD floating point");
 }
 
-pub(super) extern "x86-interrupt" fn page_fault(_: InterruptStackFrame, ec: PageFaultErrorCode) {
+/// Handle a control-flow enforcement violation, whose error code tells which instruction
+/// caused it.
+fn control_protection(frame: &TrapFrame) -> ! {
+    let cause = match frame.error_code & 0x7FFF {
+        1 => "near return address mismatch",
+        2 => "far return address mismatch",
+        3 => "missing end branch",
+        4 => "invalid shadow stack restore token",
+        5 => "invalid shadow stack busy token",
+        _ => "unknown cause",
+    };
+
     log::error!(
-        "virtual address {:#X} caused a page fault ({:?})",
-        Cr2::read(),
-        ec
+        "control protection: {cause}{}",
+        if frame.error_code & (1 << 15) != 0 {
+            " in an enclave"
+        } else {
+            ""
+        }
     );
+    fatal(frame, "control protection");
+}
+
+fn security(frame: &TrapFrame) -> ! {
+    if frame.error_code == 1 {
+        log::error!("security exception: redirected INIT signal");
+    }
 
-    super::hlt();
+    fatal(frame, "security");
 }
 
-pub(super) extern "x86-interrupt" fn timer(_: InterruptStackFrame) {
-    unsafe {
-        super::PICS
-            .lock()
-            .notify_end_of_interrupt(super::PIC1_OFFSET);
+fn machine_check(frame: &TrapFrame) {
+    if !mce::machine_check(frame) {
+        fatal(frame, "machine check");
     }
 }
@@ -0,0 +1,353 @@
+//! Interrupt entry trampolines that save the complete register state of the interrupted
+//! context before handing control to Rust code.
+
+use core::arch::asm;
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        rflags::RFlags,
+    },
+    PrivilegeLevel, VirtAddr,
+};
+
+/// The register state of an interrupted context.
+///
+/// The layout mirrors the stack built by the trampolines: general-purpose registers pushed by
+/// [`trap_common`], the vector number and error code pushed by the per-vector trampoline, and
+/// finally the interrupt stack frame pushed by the CPU.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// The interrupt vector that was raised.
+    pub vector: u64,
+
+    /// The error code pushed by the CPU, or zero for vectors without one.
+    pub error_code: u64,
+
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Check if the interrupted context was running in user mode.
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == PrivilegeLevel::Ring3 as u64
+    }
+
+    /// Log the complete register state of the interrupted context, along with the control
+    /// registers of the current CPU.
+    pub fn dump(&self) {
+        log::error!(
+            "RIP={:#018X} RSP={:#018X} RFLAGS={:#X} ({:?})",
+            self.rip,
+            self.rsp,
+            self.rflags,
+            RFlags::from_bits_truncate(self.rflags)
+        );
+        log::error!(
+            "CS={:#06X} SS={:#06X} ({})",
+            self.cs,
+            self.ss,
+            if self.is_user() { "user" } else { "kernel" }
+        );
+        log::error!(
+            "RAX={:#018X} RBX={:#018X} RCX={:#018X} RDX={:#018X}",
+            self.rax,
+            self.rbx,
+            self.rcx,
+            self.rdx
+        );
+        log::error!(
+            "RSI={:#018X} RDI={:#018X} RBP={:#018X} R8 ={:#018X}",
+            self.rsi,
+            self.rdi,
+            self.rbp,
+            self.r8
+        );
+        log::error!(
+            "R9 ={:#018X} R10={:#018X} R11={:#018X} R12={:#018X}",
+            self.r9,
+            self.r10,
+            self.r11,
+            self.r12
+        );
+        log::error!(
+            "R13={:#018X} R14={:#018X} R15={:#018X}",
+            self.r13,
+            self.r14,
+            self.r15
+        );
+
+        let (l4_page_table, cr3_flags) = Cr3::read();
+        log::error!("CR0={:#X} ({:?})", Cr0::read_raw(), Cr0::read());
+        log::error!("CR2={:#018X}", Cr2::read_raw());
+        log::error!(
+            "CR3={:#018X} ({:?})",
+            l4_page_table.start_address(),
+            cr3_flags
+        );
+        log::error!("CR4={:#X} ({:?})", Cr4::read_raw(), Cr4::read());
+    }
+}
+
+/// Save the general-purpose registers, call [`super::handlers::dispatch`] with a pointer to
+/// the resulting [`TrapFrame`], then restore the registers and return from the interrupt.
+///
+/// Every trampoline jumps here after pushing the error code (if the CPU did not) and the
+/// vector number. The CPU aligns the stack to 16 bytes before pushing the interrupt stack
+/// frame, so after these 22 quadwords the stack is still 16-byte aligned for the call.
+///
+/// Whether `swapgs` is needed is decided from the code segment of the interrupted context, which
+/// is only reliable for vectors that cannot interrupt the kernel while it runs with the user `GS`
+/// base; the others enter through [`trap_paranoid`].
+#[naked]
+unsafe extern "C" fn trap_common() -> ! {
+    unsafe {
+        asm!(
+            // Switch to the kernel `GS` base if the CPU came from user mode.
+            "test qword ptr [rsp + 24], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "mov rdi, rsp",
+            "cld",
+            // Block access to user pages even if a user copy was interrupted. `iretq` restores
+            // the flag.
+            "pushfq",
+            "and qword ptr [rsp], ~0x40000",
+            "popfq",
+            "call {dispatch}",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            // Skip the vector number and error code.
+            "add rsp, 16",
+            // Switch back to the user `GS` base if returning to user mode.
+            "test qword ptr [rsp + 8], 3",
+            "jz 3f",
+            "swapgs",
+            "3:",
+            "iretq",
+            dispatch = sym super::handlers::dispatch,
+            options(noreturn)
+        );
+    }
+}
+
+/// The entry path of the vectors that may interrupt the kernel anywhere, even between the
+/// `SYSCALL` instruction and the `swapgs` of [`crate::syscall`], where the code segment is the
+/// kernel's but `GS` still points at user data: NMIs, debug exceptions, double faults, machine
+/// checks, and general protection faults, which the `IRETQ` returning from a system call raises
+/// after switching to the user `GS` base. Like [`trap_common`], but `swapgs` is decided by reading `IA32_GS_BASE`: the
+/// per-CPU blocks live in the higher half, so a base with the top bit clear belongs to user mode.
+/// Whether `swapgs` was needed is kept in `rbx`, which the handler preserves, to undo it on the
+/// way out.
+#[naked]
+unsafe extern "C" fn trap_paranoid() -> ! {
+    unsafe {
+        asm!(
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // Switch to the kernel `GS` base unless it is already in use.
+            "mov ecx, 0xC0000101",
+            "rdmsr",
+            "xor ebx, ebx",
+            "test edx, edx",
+            "js 2f",
+            "swapgs",
+            "mov ebx, 1",
+            "2:",
+            "mov rdi, rsp",
+            "cld",
+            // Block access to user pages even if a user copy was interrupted. `iretq` restores
+            // the flag.
+            "pushfq",
+            "and qword ptr [rsp], ~0x40000",
+            "popfq",
+            "call {dispatch}",
+            // Switch back to the `GS` base in use on entry.
+            "test ebx, ebx",
+            "jz 3f",
+            "swapgs",
+            "3:",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            // Skip the vector number and error code.
+            "add rsp, 16",
+            "iretq",
+            dispatch = sym super::handlers::dispatch,
+            options(noreturn)
+        );
+    }
+}
+
+/// Define a trampoline for a vector on which the CPU does not push an error code, entering
+/// through [`trap_common`] unless another entry path is given.
+macro trampoline {
+    ($name:ident, $vector:literal) => {
+        trampoline!($name, $vector, trap_common);
+    },
+    ($name:ident, $vector:literal, $common:ident) => {
+        #[naked]
+        pub(super) unsafe extern "C" fn $name() -> ! {
+            unsafe {
+                asm!(
+                    "push 0",
+                    concat!("push ", stringify!($vector)),
+                    "jmp {common}",
+                    common = sym $common,
+                    options(noreturn)
+                );
+            }
+        }
+    },
+}
+
+/// Define a trampoline for a vector on which the CPU pushes an error code, entering through
+/// [`trap_common`] unless another entry path is given.
+macro trampoline_with_error_code {
+    ($name:ident, $vector:literal) => {
+        trampoline_with_error_code!($name, $vector, trap_common);
+    },
+    ($name:ident, $vector:literal, $common:ident) => {
+        #[naked]
+        pub(super) unsafe extern "C" fn $name() -> ! {
+            unsafe {
+                asm!(
+                    concat!("push ", stringify!($vector)),
+                    "jmp {common}",
+                    common = sym $common,
+                    options(noreturn)
+                );
+            }
+        }
+    },
+}
+
+trampoline!(divide_error, 0);
+trampoline!(debug, 1, trap_paranoid);
+trampoline!(non_maskable_interrupt, 2, trap_paranoid);
+trampoline!(breakpoint, 3);
+trampoline!(overflow, 4);
+trampoline!(bound_range_exceeded, 5);
+trampoline!(invalid_opcode, 6);
+trampoline!(device_not_available, 7);
+trampoline_with_error_code!(double_fault, 8, trap_paranoid);
+trampoline_with_error_code!(invalid_tss, 10);
+trampoline_with_error_code!(segment_not_present, 11);
+trampoline_with_error_code!(stack_segment_fault, 12);
+trampoline_with_error_code!(general_protection_fault, 13, trap_paranoid);
+trampoline_with_error_code!(page_fault, 14);
+trampoline!(x87_floating_point, 16);
+trampoline_with_error_code!(alignment_check, 17);
+trampoline!(machine_check, 18, trap_paranoid);
+trampoline!(simd_floating_point, 19);
+trampoline!(virtualization, 20);
+trampoline_with_error_code!(cp_protection_exception, 21);
+trampoline!(hv_injection_exception, 28);
+trampoline_with_error_code!(vmm_communication_exception, 29);
+trampoline_with_error_code!(security_exception, 30);
+
+/// The distance between the entry stubs in [`irq_stubs`].
+const IRQ_STUB_SIZE: u64 = 16;
+
+/// Entry stubs for the 224 vectors from 32 upwards, [`IRQ_STUB_SIZE`] bytes apart, which push
+/// a zero error code and their vector number like the exception trampolines.
+#[naked]
+unsafe extern "C" fn irq_stubs() -> ! {
+    unsafe {
+        asm!(
+            ".set vector, 32",
+            ".rept 224",
+            ".balign 16",
+            "pushq $0",
+            "pushq $vector",
+            "jmp {common}",
+            ".set vector, vector + 1",
+            ".endr",
+            common = sym trap_common,
+            options(att_syntax, noreturn)
+        );
+    }
+}
+
+/// Return the address of the entry stub for `vector`, which must be at least 32.
+pub(super) fn irq_stub(vector: u8) -> VirtAddr {
+    assert!(vector >= 32, "vector {vector} is reserved for exceptions");
+
+    // The stubs start at the first 16-byte boundary of `irq_stubs`.
+    let base = VirtAddr::from_ptr(irq_stubs as *const ()).align_up(IRQ_STUB_SIZE);
+    base + (vector as u64 - 32) * IRQ_STUB_SIZE
+}
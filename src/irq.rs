@@ -0,0 +1,349 @@
+//! Interrupt request (IRQ) lines: drivers register handlers on lines, and the entry stubs of
+//! every vector from 32 upwards dispatch to them.
+//!
+//! Line `n` is delivered on vector [`FIRST_VECTOR`]` + n`. Lines 0 to 15 are the ISA IRQs, which
+//! are masked at the PIC or I/O APIC while no handler is registered; the remaining lines are
+//! delivered by sources the registering driver programs itself, such as MSIs or the Local APIC.
+//! The line of [`crate::syscall::VECTOR`] is reserved for system calls.
+//!
+//! Lines for sources a driver programs itself are handed out by [`allocate`], which skips the ISA
+//! lines and the lines with fixed uses.
+//!
+//! Every interrupt is counted on the CPU that received it by [`stats`].
+
+pub mod softirq;
+pub mod stats;
+pub mod tasklet;
+
+use crate::{
+    apic::{self, lapic},
+    idt::{self, TrapFrame},
+    sync::{IrqRwLock, IrqSpinLock},
+    syscall,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The vector line 0 is delivered on.
+pub const FIRST_VECTOR: u8 = idt::PIC1_OFFSET;
+
+/// The amount of lines, one for each vector not reserved for exceptions.
+pub const LINES: usize = 256 - FIRST_VECTOR as usize;
+
+/// The amount of lines that correspond to ISA IRQs.
+const ISA_LINES: u8 = 16;
+
+/// The maximum amount of handlers sharing a line.
+pub const MAX_SHARED: usize = 4;
+
+/// Whether a handler recognized the interrupt as raised by its device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotMine,
+}
+
+pub type Handler = fn(&mut TrapFrame) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// There is no such line.
+    InvalidLine,
+
+    /// A handler with the same name is already registered on the line.
+    AlreadyRegistered,
+
+    /// The line is shared by the maximum amount of handlers.
+    Full,
+}
+
+/// The lines [`allocate`] never hands out, other than the ISA lines.
+const FIXED_VECTORS: [u8; 4] = [
+    syscall::VECTOR,
+    lapic::TIMER_VECTOR,
+    lapic::ERROR_VECTOR,
+    lapic::SPURIOUS_VECTOR,
+];
+
+#[derive(Clone, Copy)]
+struct Action {
+    handler: Handler,
+    name: &'static str,
+}
+
+struct Line {
+    actions: IrqRwLock<[Option<Action>; MAX_SHARED]>,
+
+    /// The amount of interrupts no handler recognized.
+    unhandled: AtomicU64,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self {
+            actions: IrqRwLock::new([None; MAX_SHARED]),
+            unhandled: AtomicU64::new(0),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const LINE: Line = Line::new();
+
+static TABLE: [Line; LINES] = [LINE; LINES];
+
+/// A bitmap of the lines handed out by [`allocate`].
+static ALLOCATED: IrqSpinLock<[u64; LINES.div_ceil(64)]> =
+    IrqSpinLock::new([0; LINES.div_ceil(64)]);
+
+/// Return the vector `line` is delivered on.
+pub const fn vector(line: u8) -> u8 {
+    FIRST_VECTOR + line
+}
+
+/// Return the line delivered on `vector`, which must not be an exception vector.
+pub const fn line(vector: u8) -> u8 {
+    vector - FIRST_VECTOR
+}
+
+/// Mask or unmask an ISA line at whichever interrupt controller is in use.
+fn set_masked(line: u8, masked: bool) {
+    if line >= ISA_LINES {
+        return;
+    }
+
+    if apic::is_enabled() {
+        apic::set_irq_masked(line, masked);
+    } else {
+        idt::set_pic_masked(line, masked);
+    }
+}
+
+/// Register `handler` on `line` under `name`. Lines may be shared by up to [`MAX_SHARED`]
+/// handlers, which are called in registration order until one recognizes the interrupt.
+///
+/// ISA lines are unmasked when their first handler is registered.
+pub fn register(line: u8, handler: Handler, name: &'static str) -> Result<(), RegisterError> {
+    {
+        let mut actions = TABLE
+            .get(line as usize)
+            .ok_or(RegisterError::InvalidLine)?
+            .actions
+            .write();
+
+        if actions.iter().flatten().any(|action| action.name == name) {
+            return Err(RegisterError::AlreadyRegistered);
+        }
+
+        let first = actions.iter().all(Option::is_none);
+        let slot = actions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterError::Full)?;
+
+        *slot = Some(Action { handler, name });
+
+        if first {
+            set_masked(line, false);
+        }
+    }
+
+    log::debug!("registered IRQ {line} handler {name}");
+    Ok(())
+}
+
+/// Remove the handler registered on `line` under `name`. Returns `false` if there is none.
+///
+/// ISA lines are masked again when their last handler is removed.
+pub fn unregister(line: u8, name: &str) -> bool {
+    let Some(entry) = TABLE.get(line as usize) else {
+        return false;
+    };
+
+    let mut actions = entry.actions.write();
+
+    let Some(slot) = actions
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|action| action.name == name))
+    else {
+        return false;
+    };
+
+    *slot = None;
+
+    // Keep the remaining handlers in registration order.
+    actions.sort_by_key(Option::is_none);
+
+    if actions.iter().all(Option::is_none) {
+        set_masked(line, true);
+    }
+
+    true
+}
+
+/// Check if any handler is registered on `line`.
+pub fn has_handlers(line: u8) -> bool {
+    TABLE
+        .get(line as usize)
+        .is_some_and(|line| line.actions.read().iter().any(Option::is_some))
+}
+
+fn is_allocatable(line: u8) -> bool {
+    line >= ISA_LINES && !FIXED_VECTORS.contains(&vector(line))
+}
+
+/// Reserve `count` consecutive lines, the first of which is a multiple of `align`, for a source
+/// the caller programs itself. Returns the first line, or `None` if there is no such range free.
+///
+/// # Panics
+///
+/// This function will panic if `align` is not a power of two.
+pub fn allocate(count: usize, align: usize) -> Option<u8> {
+    assert!(
+        align.is_power_of_two(),
+        "irq: alignment {align} is not a power of two"
+    );
+
+    let mut allocated = ALLOCATED.lock();
+    let is_free =
+        |line: usize| is_allocatable(line as u8) && allocated[line / 64] & (1 << (line % 64)) == 0;
+
+    let first = (0..LINES)
+        .step_by(align)
+        .take_while(|first| first + count <= LINES)
+        .find(|&first| (first..first + count).all(is_free))?;
+
+    for line in first..first + count {
+        allocated[line / 64] |= 1 << (line % 64);
+    }
+
+    Some(first as u8)
+}
+
+/// Release `count` lines starting at `first`, which must have been reserved by [`allocate`].
+pub fn free(first: u8, count: usize) {
+    let mut allocated = ALLOCATED.lock();
+
+    for line in first as usize..first as usize + count {
+        allocated[line / 64] &= !(1 << (line % 64));
+    }
+}
+
+/// Return the amount of interrupts received on `line` by any CPU.
+pub fn count(line: u8) -> u64 {
+    stats::count(vector(line))
+}
+
+/// Return the amount of interrupts received on `line` that no handler recognized.
+pub fn unhandled(line: u8) -> u64 {
+    TABLE
+        .get(line as usize)
+        .map_or(0, |line| line.unhandled.load(Ordering::Relaxed))
+}
+
+/// Dispatch an interrupt to the handlers of its line, signal the end of the interrupt and run
+/// the pending softirqs.
+///
+/// This is called by the IDT entry stubs, with interrupts disabled.
+pub(crate) fn dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let line = &TABLE[self::line(vector) as usize];
+
+    // The 8259 PIC raises IRQ 7 or 15 when a line drops before the CPU acknowledges it, and the
+    // Local APIC raises its spurious vector when the interrupt it was delivering went away.
+    // Neither has a device behind it, nor must it be acknowledged as an interrupt.
+    if !apic::is_enabled() && idt::is_spurious_pic_irq(vector) {
+        stats::record_spurious_pic();
+        return;
+    }
+
+    stats::record(vector);
+
+    if vector == lapic::SPURIOUS_VECTOR {
+        return;
+    }
+
+    // Handlers are called without the lock held, so that they may register other handlers.
+    let actions = *line.actions.read();
+    let handled = actions
+        .iter()
+        .flatten()
+        .any(|action| (action.handler)(frame) == IrqReturn::Handled);
+
+    if !handled {
+        let unhandled = line.unhandled.fetch_add(1, Ordering::Relaxed) + 1;
+
+        // Only report the first of a storm of interrupts.
+        if unhandled.is_power_of_two() {
+            log::warn!(
+                "{unhandled} unhandled interrupt(s) on IRQ {} (vector {vector:#X})",
+                self::line(vector)
+            );
+        }
+    }
+
+    idt::end_of_interrupt(vector);
+    softirq::run();
+}
+
+pub fn init() {
+    tasklet::init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn allocates_aligned_lines() {
+        let first = allocate(4, 4).expect("no lines left");
+        assert_eq!(first % 4, 0);
+        assert!((first..first + 4).all(is_allocatable));
+
+        free(first, 4);
+    }
+
+    #[test]
+    fn never_allocates_reserved_lines() {
+        let lines: Vec<u8> = core::iter::from_fn(|| allocate(1, 1)).collect();
+        assert!(!lines.is_empty());
+
+        for &line in &lines {
+            assert!(line >= ISA_LINES, "ISA line {line} allocated");
+            assert!(
+                !FIXED_VECTORS.contains(&vector(line)),
+                "fixed vector {:#X} allocated",
+                vector(line)
+            );
+        }
+
+        for &line in &lines {
+            free(line, 1);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        fn handler(_: &mut TrapFrame) -> IrqReturn {
+            IrqReturn::NotMine
+        }
+
+        let line = LINES as u8;
+
+        assert_eq!(
+            register(line, handler, "invalid"),
+            Err(RegisterError::InvalidLine)
+        );
+        assert!(!unregister(line, "invalid"));
+        assert!(!has_handlers(u8::MAX));
+    }
+
+    #[test]
+    fn reuses_freed_lines() {
+        let first = allocate(4, 4).expect("no lines left");
+        free(first, 4);
+
+        assert_eq!(allocate(4, 4), Some(first));
+        free(first, 4);
+    }
+}
@@ -0,0 +1,99 @@
+//! Softirqs: handlers raised from hard IRQ context and run on the way out of the interrupt, with
+//! interrupts enabled, so that top halves only need to do what cannot wait.
+
+use crate::{percpu::PerCpu, sync::IrqSpinLock};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::instructions::interrupts;
+
+/// The softirq vectors, run in this order when several are pending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Softirq {
+    Timer,
+    Tasklet,
+}
+
+const COUNT: usize = 2;
+
+/// How many times softirqs raised while running softirqs are picked up on the same interrupt
+/// exit, after which they are left for the idle loop so that a storm cannot starve it.
+const MAX_RESTARTS: usize = 10;
+
+pub type Handler = fn();
+
+static HANDLERS: IrqSpinLock<[Option<Handler>; COUNT]> = IrqSpinLock::new([None; COUNT]);
+
+/// A bitmap of the softirqs raised on each CPU.
+static PENDING: PerCpu<AtomicU32> = PerCpu::new(|| AtomicU32::new(0));
+
+/// Set while a CPU runs softirqs, to avoid running them again from a nested interrupt.
+static RUNNING: PerCpu<AtomicBool> = PerCpu::new(|| AtomicBool::new(false));
+
+/// Install `handler` for `softirq`.
+///
+/// # Panics
+///
+/// This function will panic if `softirq` already has a handler.
+pub fn open(softirq: Softirq, handler: Handler) {
+    let slot = &mut HANDLERS.lock()[softirq as usize];
+    assert!(slot.is_none(), "softirq {softirq:?} opened twice");
+
+    *slot = Some(handler);
+}
+
+/// Mark `softirq` as pending on the current CPU. It runs on the next interrupt exit or in the
+/// idle loop, whichever comes first.
+pub fn raise(softirq: Softirq) {
+    PENDING
+        .get()
+        .fetch_or(1 << softirq as u32, Ordering::Relaxed);
+}
+
+/// Check if any softirq is pending on the current CPU.
+pub fn is_pending() -> bool {
+    PENDING.get().load(Ordering::Relaxed) != 0
+}
+
+/// Check if the current context is a softirq handler.
+pub fn in_softirq() -> bool {
+    RUNNING.get().load(Ordering::Relaxed)
+}
+
+/// Run the softirqs pending on the current CPU.
+///
+/// This must be called with interrupts disabled. They are enabled while the handlers run, and
+/// disabled again before returning. Nothing is done when called from within a handler, as the
+/// outer call picks up whatever was raised in the meantime.
+pub(crate) fn run() {
+    let running = RUNNING.get();
+    let raised = PENDING.get();
+
+    if running.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    for _ in 0..MAX_RESTARTS {
+        let pending = raised.swap(0, Ordering::Relaxed);
+        if pending == 0 {
+            break;
+        }
+
+        let handlers = *HANDLERS.lock();
+
+        interrupts::enable();
+
+        for (n, handler) in handlers.iter().enumerate() {
+            if pending & (1 << n) == 0 {
+                continue;
+            }
+
+            match handler {
+                Some(handler) => handler(),
+                None => log::warn!("softirq {n} raised without a handler"),
+            }
+        }
+
+        interrupts::disable();
+    }
+
+    running.store(false, Ordering::Release);
+}
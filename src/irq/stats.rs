@@ -0,0 +1,139 @@
+//! Interrupt statistics: how many times each vector was raised on each CPU, and how many
+//! interrupts the 8259 PIC raised spuriously.
+
+use super::{line, FIRST_VECTOR, TABLE};
+use crate::{
+    apic::lapic,
+    drivers::uart::serial_print,
+    percpu,
+    smp::{self, MAX_CPUS},
+    syscall,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The mnemonics of the exception vectors, or an empty string for reserved vectors.
+const EXCEPTIONS: [&str; FIRST_VECTOR as usize] = [
+    "DE", "DB", "NMI", "BP", "OF", "BR", "UD", "NM", "DF", "", "TS", "NP", "SS", "GP", "PF", "",
+    "MF", "AC", "MC", "XM", "VE", "CP", "", "", "", "", "", "", "HV", "VC", "SX", "",
+];
+
+// Only used to initialize the counters.
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const VECTORS: [AtomicU64; 256] = [ZERO; 256];
+
+/// The amount of times each vector was raised on each CPU. These are plain arrays rather than
+/// [`crate::percpu::PerCpu`] so that an NMI never waits for them to be created.
+static COUNTS: [[AtomicU64; 256]; MAX_CPUS] = [VECTORS; MAX_CPUS];
+
+/// The amount of spurious interrupts the 8259 PIC raised on each CPU.
+static SPURIOUS_PIC: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+/// Account for `vector` being raised on the current CPU.
+pub(crate) fn record(vector: u8) {
+    COUNTS[percpu::current_cpu()][vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Account for a spurious interrupt from the 8259 PIC on the current CPU.
+pub(super) fn record_spurious_pic() {
+    SPURIOUS_PIC[percpu::current_cpu()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Return the amount of times `vector` was raised on the CPU numbered `cpu`.
+pub fn count_on(cpu: usize, vector: u8) -> u64 {
+    COUNTS[cpu][vector as usize].load(Ordering::Relaxed)
+}
+
+/// Return the amount of times `vector` was raised on any CPU.
+pub fn count(vector: u8) -> u64 {
+    (0..smp::cpu_count()).map(|cpu| count_on(cpu, vector)).sum()
+}
+
+/// Return the amount of spurious interrupts the 8259 PIC raised on any CPU.
+pub fn spurious_pic() -> u64 {
+    (0..smp::cpu_count())
+        .map(|cpu| SPURIOUS_PIC[cpu].load(Ordering::Relaxed))
+        .sum()
+}
+
+/// Print the label of a row followed by its count on every CPU.
+fn print_counts(label: &dyn core::fmt::Display, count_on: impl Fn(usize) -> u64) {
+    serial_print!("{label:>4}:");
+
+    for cpu in 0..smp::cpu_count() {
+        serial_print!(" {:>10}", count_on(cpu));
+    }
+}
+
+/// Print a table of the interrupts raised on every CPU to the serial port, in the format of
+/// `/proc/interrupts`: exceptions that were raised, lines with handlers or interrupts, and the
+/// spurious interrupts of the 8259 PIC.
+pub fn print() {
+    serial_print!("     ");
+    for cpu in 0..smp::cpu_count() {
+        serial_print!(" {:>10}", format_args!("CPU{cpu}"));
+    }
+    serial_print!("\n");
+
+    for (vector, name) in EXCEPTIONS.iter().enumerate() {
+        let vector = vector as u8;
+
+        if count(vector) != 0 {
+            print_counts(name, |cpu| count_on(cpu, vector));
+            serial_print!("   {vector:#04X}  exception\n");
+        }
+    }
+
+    for vector in FIRST_VECTOR..=u8::MAX {
+        let line_number = line(vector);
+        let line = &TABLE[line_number as usize];
+        let actions = *line.actions.read();
+
+        if count(vector) == 0 && actions.iter().all(Option::is_none) {
+            continue;
+        }
+
+        print_counts(&line_number, |cpu| count_on(cpu, vector));
+        serial_print!("   {vector:#04X} ");
+
+        match vector {
+            syscall::VECTOR => {
+                serial_print!(" system call");
+            }
+            lapic::SPURIOUS_VECTOR => {
+                serial_print!(" local APIC spurious");
+            }
+            _ => {
+                for action in actions.iter().flatten() {
+                    serial_print!(" {}", action.name);
+                }
+            }
+        }
+
+        let unhandled = line.unhandled.load(Ordering::Relaxed);
+        if unhandled != 0 {
+            serial_print!(" ({unhandled} unhandled)");
+        }
+
+        serial_print!("\n");
+    }
+
+    print_counts(&"SPU", |cpu| SPURIOUS_PIC[cpu].load(Ordering::Relaxed));
+    serial_print!("         8259 PIC spurious\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_exceptions() {
+        const BREAKPOINT: u8 = 3;
+
+        let before = count(BREAKPOINT);
+        x86_64::instructions::interrupts::int3();
+
+        assert_eq!(count(BREAKPOINT), before + 1);
+    }
+}
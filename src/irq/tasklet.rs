@@ -0,0 +1,90 @@
+//! Tasklets: statically allocated callbacks scheduled from any context and run from the
+//! [`Softirq::Tasklet`] softirq.
+//!
+//! A tasklet is queued at most once no matter how often it is scheduled before it runs, and may
+//! be scheduled again from its own callback.
+
+use super::softirq::{self, Softirq};
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+pub struct Tasklet {
+    func: fn(),
+    scheduled: AtomicBool,
+
+    /// The next tasklet in the queue, while this one is scheduled.
+    next: AtomicPtr<Tasklet>,
+}
+
+/// The most recently scheduled tasklet, which links to the ones scheduled before it.
+static QUEUE: AtomicPtr<Tasklet> = AtomicPtr::new(ptr::null_mut());
+
+impl Tasklet {
+    pub const fn new(func: fn()) -> Self {
+        Self {
+            func,
+            scheduled: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Queue the tasklet to run, unless it is already queued.
+    ///
+    /// This neither allocates nor takes locks, so it may be called from hard IRQ context.
+    pub fn schedule(&'static self) {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let this = self as *const Self as *mut Self;
+        let mut head = QUEUE.load(Ordering::Relaxed);
+
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+
+            match QUEUE.compare_exchange_weak(head, this, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => head = actual,
+            }
+        }
+
+        softirq::raise(Softirq::Tasklet);
+    }
+
+    /// Check if the tasklet is queued and has not started running yet.
+    pub fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Relaxed)
+    }
+}
+
+/// Run every queued tasklet in the order they were scheduled.
+fn run() {
+    let mut reversed = QUEUE.swap(ptr::null_mut(), Ordering::Acquire);
+    let mut queue = ptr::null_mut();
+
+    while !reversed.is_null() {
+        // SAFETY: only `'static` tasklets are queued.
+        let tasklet = unsafe { &*reversed };
+
+        reversed = tasklet.next.load(Ordering::Relaxed);
+        tasklet.next.store(queue, Ordering::Relaxed);
+        queue = tasklet as *const Tasklet as *mut Tasklet;
+    }
+
+    while !queue.is_null() {
+        // SAFETY: as above.
+        let tasklet = unsafe { &*queue };
+
+        // Scheduling the tasklet again overwrites its link, so follow it first.
+        queue = tasklet.next.load(Ordering::Relaxed);
+        tasklet.scheduled.store(false, Ordering::Release);
+
+        (tasklet.func)();
+    }
+}
+
+pub(super) fn init() {
+    softirq::open(Softirq::Tasklet, run);
+}
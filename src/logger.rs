@@ -1,4 +1,7 @@
-use core::panic::PanicInfo;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use log::{
     Level::{self, *},
     LevelFilter, Log, Metadata, MetadataBuilder, Record, RecordBuilder,
@@ -51,6 +54,30 @@ impl Log for SystemLogger {
 
 static LOGGER: SystemLogger = SystemLogger;
 
+/// How many times the console locks are tried before they are taken away from their holder
+/// while [`bust_spinlocks`] is on.
+const BUST_SPINS: usize = 1 << 20;
+
+/// The amount of CPUs reporting from NMI or machine check context.
+static BUSTING: AtomicUsize = AtomicUsize::new(0);
+
+/// Start or stop reporting from a context `cli` does not keep out, such as an NMI or a machine
+/// check. While on, the console locks are taken away from their holder after a bounded wait, so
+/// that a report about a CPU stuck holding them still gets out.
+pub fn bust_spinlocks(on: bool) {
+    if on {
+        BUSTING.fetch_add(1, Ordering::Acquire);
+    } else {
+        BUSTING.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Return how many times a console lock should be tried before it is busted, or `None` to wait
+/// for it as usual.
+pub(crate) fn bust_spins() -> Option<usize> {
+    (BUSTING.load(Ordering::Acquire) != 0).then_some(BUST_SPINS)
+}
+
 /// Log panics.
 pub fn log_panic(info: &PanicInfo<'_>) {
     let location = info.location().unwrap();
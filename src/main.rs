@@ -4,11 +4,13 @@
 #![deny(unsafe_op_in_unsafe_fn, rust_2018_idioms)]
 #![feature(
     decl_macro,
-    abi_x86_interrupt,
+    allocator_api,
     custom_test_frameworks,
     panic_info_message,
-    int_roundings
+    int_roundings,
+    naked_functions
 )]
+#![cfg_attr(feature = "lockdep", feature(const_caller_location))]
 #![test_runner(crate::tests::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -19,11 +21,25 @@ compile_error!("monoOS is only designed for 64-bit architectures");
 compile_error!("monoOS only supports the x86-64 architecture");
 
 mod acpi;
+mod apic;
+mod backtrace;
+mod console;
+mod cpu;
 mod drivers;
 mod gdt;
 mod idt;
+mod irq;
 mod logger;
+mod mce;
 mod mem;
+mod percpu;
+mod power;
+mod smp;
+mod sync;
+mod syscall;
+mod time;
+mod watchdog;
+mod workqueue;
 
 #[cfg(test)]
 mod tests;
@@ -36,12 +52,18 @@ extern crate alloc;
 
 use core::{panic::PanicInfo, sync::atomic::Ordering};
 use idt::hlt;
-use limine::{FramebufferRequest, HhdmRequest, MemmapRequest};
-use x86_64::instructions::interrupts;
+use limine::{
+    FramebufferRequest, HhdmRequest, KernelFileRequest, MemmapRequest, RsdpRequest, SmpRequest,
+};
+use mem::VirtToPhys;
+use x86_64::{instructions::interrupts, VirtAddr};
 
 static FRAMEBUFFER: FramebufferRequest = FramebufferRequest::new(0);
 static HHDM: HhdmRequest = HhdmRequest::new(0);
+static KERNEL_FILE: KernelFileRequest = KernelFileRequest::new(0);
 static MEMMAP: MemmapRequest = MemmapRequest::new(0);
+static RSDP: RsdpRequest = RsdpRequest::new(0);
+static SMP: SmpRequest = SmpRequest::new(0);
 
 #[no_mangle]
 extern "C" fn kinit() -> ! {
@@ -50,9 +72,34 @@ extern "C" fn kinit() -> ! {
 
         logger::init();
 
+        backtrace::init(
+            KERNEL_FILE
+                .get_response()
+                .get()
+                .and_then(|response| response.kernel_file.get()),
+        );
+
+        percpu::init(0);
         gdt::init();
+
+        #[cfg(feature = "lockdep")]
+        {
+            sync::lockdep::init();
+            log::info!("initialized lock validation");
+        }
+
+        cpu::init();
+        log::info!("initialized FPU and SIMD support on {}", cpu::info());
+
+        syscall::init();
         idt::init();
 
+        mce::init();
+        log::info!("initialized machine check handling");
+
+        irq::init();
+        log::info!("initialized IRQ handling");
+
         let physical_memory_offset = HHDM
             .get_response()
             .get()
@@ -70,6 +117,39 @@ extern "C" fn kinit() -> ! {
         mem::init(memmap);
         log::info!("initialized memory allocation facilities");
 
+        let rsdp = RSDP
+            .get_response()
+            .get()
+            .and_then(|response| response.address.as_ptr())
+            .expect("unable to obtain RSDP");
+
+        acpi::init(VirtAddr::from_ptr(rsdp).to_phys());
+        log::info!("initialized ACPI");
+
+        apic::init(acpi::platform_info());
+
+        drivers::hpet::init(acpi::hpet_info());
+        if drivers::hpet::get().is_some() {
+            log::info!("initialized HPET");
+        } else {
+            log::warn!("continuing without HPET");
+        }
+
+        drivers::rtc::init(acpi::rtc_century_register());
+        log::info!("initialized RTC");
+
+        time::init();
+        log::info!("initialized timekeeping");
+
+        smp::init(SMP.get_response().get_mut());
+        log::info!("initialized SMP");
+
+        watchdog::init();
+        log::info!("initialized watchdog");
+
+        console::init();
+        log::info!("initialized debug console");
+
         // let framebuffer = &*FRAMEBUFFER
         //     .get_response()
         //     .get()
@@ -83,14 +163,42 @@ extern "C" fn kinit() -> ! {
     });
 
     #[cfg(test)]
-    test_main();
+    {
+        test_main();
+        tests::exit_qemu(tests::ExitCode::Success);
+    }
 
-    hlt()
+    idle()
+}
+
+/// Run deferred work as it comes in, halting while there is none.
+fn idle() -> ! {
+    loop {
+        interrupts::disable();
+        watchdog::touch_idle();
+
+        // Softirqs left over by an interrupt that raised too many of them.
+        irq::softirq::run();
+
+        if workqueue::has_pending() {
+            interrupts::enable();
+            workqueue::run_pending();
+        } else {
+            // Enabling interrupts only takes effect after the next instruction, so none can
+            // queue work between the check and halting.
+            interrupts::enable_and_hlt();
+        }
+    }
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo<'_>) -> ! {
     logger::log_panic(info);
+    backtrace::print_panic();
+
+    // Exit QEMU instead of hanging when a test fails.
+    #[cfg(test)]
+    tests::exit_qemu(tests::ExitCode::Failure);
 
     hlt()
 }
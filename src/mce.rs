@@ -0,0 +1,366 @@
+//! Machine check architecture: hardware errors reported through the MCA banks of the CPU.
+//!
+//! Errors the hardware corrected, and those it could not correct without the interrupted context
+//! having consumed the data, are logged and the machine check exception returns. Anything else
+//! cannot be recovered from and panics. QEMU injects errors with its `mce` monitor command, such
+//! as `mce 0 1 0xb200000000000000 0 0 0` for an uncorrected error with context corrupted.
+
+use crate::{
+    cpu::{self, Feature, Vendor},
+    idt::TrapFrame,
+    logger, percpu,
+};
+use core::fmt;
+use log::Level;
+use x86_64::registers::{
+    control::{Cr4, Cr4Flags},
+    model_specific::Msr,
+};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+
+/// The first MSR of bank 0. Each bank has four: control, status, address and miscellaneous.
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xFF;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+/// Software error recovery: uncorrected errors report whether they can be recovered from.
+const MCG_CAP_SER_P: u64 = 1 << 24;
+
+/// The interrupted instruction can be restarted.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+
+/// The interrupted instruction is the one that caused the error.
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+
+/// Processor context corrupt.
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+/// Signalled through a machine check exception rather than only logged.
+const MCI_STATUS_S: u64 = 1 << 56;
+
+/// Action required before the interrupted context may continue.
+const MCI_STATUS_AR: u64 = 1 << 55;
+
+/// The MSRs of an MCA bank.
+#[derive(Debug, Clone, Copy)]
+struct Bank(u32);
+
+impl Bank {
+    fn msr(self, offset: u32) -> Msr {
+        Msr::new(IA32_MC0_CTL + self.0 * 4 + offset)
+    }
+
+    fn read(self, offset: u32) -> u64 {
+        // SAFETY: the bank is below the count reported by IA32_MCG_CAP.
+        unsafe { self.msr(offset).read() }
+    }
+
+    fn write(self, offset: u32, value: u64) {
+        // SAFETY: as above. Writes only enable reporting or clear logged errors.
+        unsafe { self.msr(offset).write(value) }
+    }
+
+    fn status(self) -> u64 {
+        self.read(1)
+    }
+
+    fn clear(self) {
+        self.write(1, 0);
+    }
+}
+
+/// How an error affects the context it interrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    /// Corrected by the hardware.
+    Corrected,
+
+    /// Not corrected, but the data has not been consumed, so execution may continue.
+    Deferred,
+
+    /// Not corrected and consumed by the interrupted context, which cannot continue.
+    Fatal,
+}
+
+impl Severity {
+    fn of(status: u64, ser: bool) -> Self {
+        if status & MCI_STATUS_UC == 0 {
+            return Self::Corrected;
+        }
+
+        if status & MCI_STATUS_PCC != 0 || !ser {
+            return Self::Fatal;
+        }
+
+        // Recovering from an error that requires action means giving up on whatever consumed
+        // the data, and the kernel cannot give up on itself.
+        if status & (MCI_STATUS_S | MCI_STATUS_AR) == MCI_STATUS_S | MCI_STATUS_AR {
+            return Self::Fatal;
+        }
+
+        Self::Deferred
+    }
+}
+
+/// The architectural part of an MCA error code.
+struct ErrorCode(u16);
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const LEVELS: [&str; 4] = ["L0", "L1", "L2", "generic"];
+        const TYPES: [&str; 4] = ["instruction", "data", "generic", "reserved"];
+        const REQUESTS: [&str; 9] = [
+            "generic",
+            "read",
+            "write",
+            "data read",
+            "data write",
+            "instruction fetch",
+            "prefetch",
+            "eviction",
+            "snoop",
+        ];
+
+        // Bit 12 only tells whether corrected errors of the same kind are being filtered.
+        let code = self.0 & !(1 << 12);
+        let level = LEVELS[(code & 0b11) as usize];
+        let kind = TYPES[(code >> 2 & 0b11) as usize];
+        let request = REQUESTS
+            .get((code >> 4 & 0xF) as usize)
+            .unwrap_or(&"reserved");
+
+        match code {
+            0x0000 => write!(f, "no error"),
+            0x0001 => write!(f, "unclassified error"),
+            0x0002 => write!(f, "microcode ROM parity error"),
+            0x0003 => write!(f, "external error"),
+            0x0004 => write!(f, "FRC error"),
+            0x0005 => write!(f, "internal parity error"),
+            0x0006 => write!(f, "SMM handler code access violation"),
+            0x0400 => write!(f, "internal timer error"),
+            0x0E0B => write!(f, "I/O error"),
+            _ if code & 0xFC00 == 0x0400 => write!(f, "internal unclassified error"),
+            _ if code & 0xFFF0 == 0x0010 => write!(f, "{level} {kind} TLB error"),
+            _ if code & 0xFF80 == 0x0080 => write!(
+                f,
+                "memory controller error ({}) on channel {}",
+                ["generic", "read", "write", "address/command", "scrubbing"]
+                    .get((code >> 4 & 0b111) as usize)
+                    .unwrap_or(&"reserved"),
+                code & 0xF
+            ),
+            _ if code & 0xFF00 == 0x0100 => {
+                write!(f, "{level} {kind} cache error on {request}")
+            }
+            _ if code & 0xF800 == 0x0800 => write!(f, "{level} bus error on {request}"),
+            _ => write!(f, "unknown error {code:#06X}"),
+        }
+    }
+}
+
+/// Return the amount of MCA banks and whether software error recovery is supported, if machine
+/// checks are supported at all.
+fn capabilities() -> Option<(u32, bool)> {
+    if !cpu::has(Feature::Mce) || !cpu::has(Feature::Mca) {
+        return None;
+    }
+
+    // SAFETY: the MCA MSRs exist if CPUID reports MCA.
+    let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    Some(((cap & MCG_CAP_COUNT) as u32, cap & MCG_CAP_SER_P != 0))
+}
+
+/// Log the error in `bank`, if there is one. Returns its severity.
+fn log_bank(bank: Bank, ser: bool) -> Option<Severity> {
+    let status = bank.status();
+    if status & MCI_STATUS_VAL == 0 {
+        return None;
+    }
+
+    let severity = Severity::of(status, ser);
+    let level = match severity {
+        Severity::Corrected => Level::Warn,
+        _ => Level::Error,
+    };
+
+    log::log!(
+        level,
+        "mce: {} error in bank {} on CPU {}: {} (model code {:#06X})",
+        match severity {
+            Severity::Corrected => "corrected",
+            Severity::Deferred => "recoverable uncorrected",
+            Severity::Fatal => "fatal uncorrected",
+        },
+        bank.0,
+        percpu::current_cpu(),
+        ErrorCode(status as u16),
+        (status >> 16) as u16
+    );
+
+    log::log!(
+        level,
+        "mce: status {status:#018X}{}{}{}{}",
+        if status & MCI_STATUS_OVER != 0 {
+            ", overflowed"
+        } else {
+            ""
+        },
+        if status & MCI_STATUS_EN != 0 {
+            ", enabled"
+        } else {
+            ""
+        },
+        if status & MCI_STATUS_PCC != 0 {
+            ", context corrupt"
+        } else {
+            ""
+        },
+        if status & MCI_STATUS_AR != 0 {
+            ", action required"
+        } else {
+            ""
+        },
+    );
+
+    if status & MCI_STATUS_ADDRV != 0 {
+        log::log!(level, "mce: address {:#X}", bank.read(2));
+    }
+
+    if status & MCI_STATUS_MISCV != 0 {
+        log::log!(level, "mce: miscellaneous {:#X}", bank.read(3));
+    }
+
+    Some(severity)
+}
+
+/// Handle a machine check exception that interrupted `frame`. Returns `false` if the interrupted
+/// context cannot continue.
+///
+/// The exception cannot be masked, so it may arrive while the console locks are held; they are
+/// busted for the report, and stay so for the panic that follows a fatal error.
+pub(crate) fn machine_check(frame: &TrapFrame) -> bool {
+    logger::bust_spinlocks(true);
+
+    let Some((banks, ser)) = capabilities() else {
+        return false;
+    };
+
+    // SAFETY: the MCA MSRs exist, as the exception was raised.
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+
+    let mut worst = None;
+    for bank in (0..banks).map(Bank) {
+        let severity = log_bank(bank, ser);
+        worst = worst.max(severity);
+
+        if severity.is_some_and(|severity| severity != Severity::Fatal) {
+            bank.clear();
+        }
+    }
+
+    if mcg_status & MCG_STATUS_EIPV != 0 {
+        log::error!("mce: caused by the instruction at {:#X}", frame.rip);
+    }
+
+    if mcg_status & MCG_STATUS_RIPV == 0 || worst == Some(Severity::Fatal) {
+        return false;
+    }
+
+    // Clearing the machine check in progress flag allows the next one to be raised rather than
+    // shutting the CPU down.
+    // SAFETY: as above.
+    unsafe { Msr::new(IA32_MCG_STATUS).write(0) };
+
+    logger::bust_spinlocks(false);
+    true
+}
+
+/// Enable machine checks on the current CPU, logging errors left over from before boot.
+///
+/// This must be called after the IDT is loaded.
+pub fn init() {
+    let Some((banks, ser)) = capabilities() else {
+        log::warn!(
+            "machine checks are not supported on CPU {}",
+            percpu::current_cpu()
+        );
+        return;
+    };
+
+    let info = cpu::info();
+
+    // SAFETY: IA32_MCG_CAP reports whether IA32_MCG_CTL exists, and enabling every error
+    // source only makes more errors reported.
+    unsafe {
+        if Msr::new(IA32_MCG_CAP).read() & MCG_CAP_CTL_P != 0 {
+            Msr::new(IA32_MCG_CTL).write(u64::MAX);
+        }
+    }
+
+    for bank in (0..banks).map(Bank) {
+        // Bank 0 of older Intel CPUs reports errors the firmware owns.
+        let firmware_owned =
+            bank.0 == 0 && info.vendor == Vendor::Intel && info.family == 6 && info.model < 0x1A;
+
+        if !firmware_owned {
+            bank.write(0, u64::MAX);
+        }
+
+        if log_bank(bank, ser).is_some() {
+            bank.clear();
+        }
+    }
+
+    // SAFETY: the exception is handled now that the IDT is loaded.
+    unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+
+    log::debug!(
+        "enabled machine checks on CPU {} with {banks} bank(s)",
+        percpu::current_cpu()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn decode_error_codes() {
+        assert_eq!(format!("{}", ErrorCode(0x0005)), "internal parity error");
+        assert_eq!(format!("{}", ErrorCode(0x0011)), "L1 instruction TLB error");
+        assert_eq!(
+            format!("{}", ErrorCode(0x1136)),
+            "L2 data cache error on data read"
+        );
+        assert_eq!(
+            format!("{}", ErrorCode(0x009F)),
+            "memory controller error (read) on channel 15"
+        );
+    }
+
+    #[test]
+    fn classify_severity() {
+        let uc = MCI_STATUS_VAL | MCI_STATUS_UC;
+
+        assert_eq!(Severity::of(MCI_STATUS_VAL, true), Severity::Corrected);
+        assert_eq!(Severity::of(uc, false), Severity::Fatal);
+        assert_eq!(Severity::of(uc | MCI_STATUS_PCC, true), Severity::Fatal);
+        assert_eq!(Severity::of(uc | MCI_STATUS_S, true), Severity::Deferred);
+        assert_eq!(
+            Severity::of(uc | MCI_STATUS_S | MCI_STATUS_AR, true),
+            Severity::Fatal
+        );
+    }
+}
@@ -3,13 +3,15 @@ mod paging;
 mod pmm;
 mod vmm;
 
+pub mod mmio;
+pub mod user;
+
 use core::sync::atomic::{AtomicU64, Ordering};
 use limine::{MemmapEntry, NonNullPtr};
-use linked_list_allocator::LockedHeap;
 use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: heap::KernelHeap = heap::KernelHeap::empty();
 
 pub static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
@@ -44,3 +46,14 @@ impl PhysToVirt for PhysAddr {
         VirtAddr::new(self.as_u64() + physical_memory_offset())
     }
 }
+
+/// Convert a virtual address in the higher half direct map to a physical address.
+pub trait VirtToPhys {
+    fn to_phys(self) -> PhysAddr;
+}
+
+impl VirtToPhys for VirtAddr {
+    fn to_phys(self) -> PhysAddr {
+        PhysAddr::new(self.as_u64() - physical_memory_offset())
+    }
+}
@@ -1,6 +1,12 @@
 //! Heap allocator
 
 use super::{pmm, vmm, PhysToVirt, ALLOCATOR};
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -12,6 +18,32 @@ const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 const HEAP_START: usize = 0x444444440000;
 const HEAP_END: usize = HEAP_START + HEAP_SIZE;
 
+/// The kernel heap, locked with interrupts disabled so that interrupt handlers can allocate.
+pub(super) struct KernelHeap(IrqSpinLock<Heap>);
+
+impl KernelHeap {
+    pub(super) const fn empty() -> Self {
+        Self(IrqSpinLock::new(Heap::empty()))
+    }
+
+    pub(super) fn lock(&self) -> IrqSpinLockGuard<'_, Heap> {
+        self.0.lock()
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock()
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: `ptr` was allocated from this heap with `layout`, as guaranteed by the caller.
+        unsafe { self.lock().deallocate(NonNull::new_unchecked(ptr), layout) }
+    }
+}
+
 /// Initialize heap.
 pub(super) fn init() -> Result<(), MapToError<Size4KiB>> {
     vmm::get_vmalloc().allocate(
@@ -0,0 +1,75 @@
+//! Uncached mappings of device memory.
+//!
+//! The HHDM only covers RAM and the low 4 GiB, mapped write-back, so device registers are mapped
+//! uncached in a window of their own instead. Its address space is handed out in order and never
+//! reused: unmapping only removes the pages.
+
+use super::{paging, pmm};
+use crate::sync::IrqSpinLock;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const MMIO_START: u64 = 0xFFFF_F900_0000_0000;
+const MMIO_SIZE: u64 = 1 << 30;
+
+/// The first address of the window not handed out yet.
+static NEXT: IrqSpinLock<u64> = IrqSpinLock::new(MMIO_START);
+
+/// Map the `len` bytes of device memory at `phys` uncached, returning the address they are
+/// mapped at. Returns `None` if the window or physical memory for page tables is exhausted.
+pub fn map(phys: PhysAddr, len: usize) -> Option<VirtAddr> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::containing_address(phys + (len.max(1) - 1) as u64);
+    let size = (last - first + 1) * Size4KiB::SIZE;
+
+    let start = {
+        let mut next = NEXT.lock();
+        let start = *next;
+
+        if start + size > MMIO_START + MMIO_SIZE {
+            return None;
+        }
+
+        *next += size;
+        start
+    };
+
+    // PCD and PWT select the uncached memory type with the default PAT.
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    let mut mapper = paging::mapper();
+    let mut allocator = pmm::get_frame_allocator();
+
+    for (page, frame) in (start..)
+        .step_by(Size4KiB::SIZE as usize)
+        .zip(PhysFrame::range_inclusive(first, last))
+    {
+        let page = Page::containing_address(VirtAddr::new(page));
+
+        // SAFETY: nothing else maps pages in the window, and the frame is device memory rather
+        // than memory in use by the kernel.
+        unsafe { mapper.map_to(page, frame, flags, &mut *allocator) }
+            .ok()?
+            .flush();
+    }
+
+    Some(VirtAddr::new(start + phys.as_u64() % Size4KiB::SIZE))
+}
+
+/// Unmap the `len` bytes at `addr`, which were mapped by [`map`].
+pub fn unmap(addr: VirtAddr, len: usize) {
+    let first = Page::<Size4KiB>::containing_address(addr);
+    let last = Page::containing_address(addr + (len.max(1) - 1) as u64);
+    let mut mapper = paging::mapper();
+
+    for page in Page::range_inclusive(first, last) {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
@@ -1,7 +1,8 @@
 //! Physical memory manager/frame allocator implemented using a bitmap.
 
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
 use limine::{MemmapEntry, MemoryMapEntryType, NonNullPtr};
-use spin::{Mutex, MutexGuard, Once};
+use spin::Once;
 use x86_64::{
     structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
     PhysAddr,
@@ -146,14 +147,14 @@ impl FrameDeallocator<Size4KiB> for SystemFrameAllocator {
     }
 }
 
-static FRAME_ALLOCATOR: Once<Mutex<SystemFrameAllocator>> = Once::new();
+static FRAME_ALLOCATOR: Once<IrqSpinLock<SystemFrameAllocator>> = Once::new();
 
 /// Get a handle to the frame allocator.
 ///
 /// # Panics
 ///
 /// This function will panic if the frame allocator has not been initialized.
-pub(super) fn get_frame_allocator() -> MutexGuard<'static, SystemFrameAllocator> {
+pub(super) fn get_frame_allocator() -> IrqSpinLockGuard<'static, SystemFrameAllocator> {
     FRAME_ALLOCATOR
         .get()
         .expect("frame allocator not initialized")
@@ -162,7 +163,7 @@ pub(super) fn get_frame_allocator() -> MutexGuard<'static, SystemFrameAllocator>
 
 /// Initialize the physical memory manager.
 pub(super) fn init(memmap: &'static mut [NonNullPtr<MemmapEntry>]) {
-    FRAME_ALLOCATOR.call_once(|| Mutex::new(SystemFrameAllocator::new(memmap)));
+    FRAME_ALLOCATOR.call_once(|| IrqSpinLock::new(SystemFrameAllocator::new(memmap)));
 }
 
 #[cfg(test)]
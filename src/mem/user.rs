@@ -0,0 +1,257 @@
+//! Copying to and from user memory.
+//!
+//! With SMAP enabled the kernel faults on any access to a user page, except between `stac` and
+//! `clac`. These helpers check that the whole range is mapped for user access before opening
+//! that window around the copy itself. A mapping can still go away in between, so the copy is a
+//! single instruction listed in [`fixup`]: a fault on it resumes after it, and the copy fails.
+
+use super::{paging, PhysToVirt};
+use crate::{
+    cpu::{self, Feature},
+    syscall::Errno,
+};
+use core::arch::{asm, global_asm};
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags},
+    VirtAddr,
+};
+
+/// The end of the lower half of the address space, which belongs to user space.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Return whether the page containing `addr` is mapped for user access, and for writes if
+/// `write` is set. Every level of the page tables must allow the access.
+fn is_accessible(addr: VirtAddr, write: bool) -> bool {
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table: &PageTable = paging::active_l4_page_table();
+
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &table[index];
+        if !entry.flags().contains(required) {
+            return false;
+        }
+
+        // Entries of the level 3 and 2 tables may map 1 GiB and 2 MiB pages, and those of the
+        // level 1 table always map a page.
+        if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+
+        // SAFETY: a present entry that does not map a page points at the next table.
+        table = unsafe { &*entry.addr().to_virt().as_ptr() };
+    }
+
+    true
+}
+
+/// Check that `len` bytes at `addr` are user memory accessible as requested.
+fn check(addr: u64, len: usize, write: bool) -> Result<(), Errno> {
+    let end = addr
+        .checked_add(len as u64)
+        .filter(|&end| end <= USER_END)
+        .ok_or(Errno::Fault)?;
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    let first = addr & !(PAGE_SIZE - 1);
+    let accessible = (first..end)
+        .step_by(PAGE_SIZE as usize)
+        .all(|page| is_accessible(VirtAddr::new(page), write));
+
+    if accessible {
+        Ok(())
+    } else {
+        Err(Errno::Fault)
+    }
+}
+
+// The copy is written in assembly so that the instruction accessing user memory is known. It
+// stays in the kernel image even while no helper is used, as the page fault handler refers to it.
+global_asm!(
+    ".global copy_user",
+    "copy_user:",
+    "mov rcx, rdx",
+    ".global user_copy",
+    "user_copy:",
+    "rep movsb",
+    ".global user_copy_fixup",
+    "user_copy_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    /// Copy `len` bytes from `src` to `dst`, returning the amount of bytes left uncopied by a
+    /// fault.
+    ///
+    /// # Safety
+    ///
+    /// The ranges must not overlap, and any part of them that is mapped must be valid for the
+    /// copy.
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+
+    /// The instruction of [`copy_user`] that accesses user memory.
+    fn user_copy();
+
+    /// The instruction after it, where a fault on it resumes with the remaining count in `rcx`.
+    fn user_copy_fixup();
+}
+
+/// Return the address to resume at after a kernel mode fault at `rip`, if the faulting
+/// instruction accesses user memory on behalf of these helpers.
+pub(crate) fn fixup(rip: u64) -> Option<u64> {
+    (rip == user_copy as *const () as u64).then_some(user_copy_fixup as *const () as u64)
+}
+
+/// Run `f` with access to user pages allowed, if SMAP would otherwise prevent it.
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = cpu::has(Feature::Smap);
+
+    if smap {
+        // SAFETY: access is only allowed while `f` runs.
+        unsafe { asm!("stac", options(nostack)) };
+    }
+
+    let result = f();
+
+    if smap {
+        // SAFETY: clearing the flag only takes the access away again.
+        unsafe { asm!("clac", options(nostack)) };
+    }
+
+    result
+}
+
+/// Copy `dst.len()` bytes from the user address `src` into `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    check(src, dst.len(), false)?;
+
+    // SAFETY: the source range is user memory, which cannot overlap `dst` in the kernel, and a
+    // fault on it is recovered from.
+    let left =
+        with_user_access(|| unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) });
+
+    if left == 0 {
+        Ok(())
+    } else {
+        Err(Errno::Fault)
+    }
+}
+
+/// Copy `src` to the user address `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Errno> {
+    check(dst, src.len(), true)?;
+
+    // SAFETY: the destination range is user memory, which cannot overlap `src`, and a fault on
+    // it is recovered from.
+    let left = with_user_access(|| unsafe { copy_user(dst as *mut u8, src.as_ptr(), src.len()) });
+
+    if left == 0 {
+        Ok(())
+    } else {
+        Err(Errno::Fault)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::pmm, *};
+    use x86_64::structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    };
+
+    /// An address in the user half the kernel leaves unmapped.
+    const TEST_PAGE: u64 = 0x0000_7000_0000_0000;
+
+    /// Map a fresh page at `addr` with `flags` for user access, run `f` and unmap it again.
+    fn with_user_page(addr: u64, flags: PageTableFlags, f: impl FnOnce()) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let mut mapper = paging::mapper();
+
+        {
+            let mut allocator = pmm::get_frame_allocator();
+            let frame = allocator.allocate_frame().expect("out of memory");
+
+            // SAFETY: nothing else uses the page.
+            unsafe {
+                mapper.map_to(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | flags,
+                    &mut *allocator,
+                )
+            }
+            .expect("unable to map the test page")
+            .flush();
+        }
+
+        f();
+
+        let (frame, flush) = mapper.unmap(page).expect("test page not mapped");
+        flush.flush();
+
+        // SAFETY: the frame was only mapped at the test page.
+        unsafe { pmm::get_frame_allocator().deallocate_frame(frame) };
+    }
+
+    #[test]
+    fn rejects_kernel_addresses() {
+        let mut buf = [0; 16];
+        let kernel = buf.as_ptr() as u64;
+
+        assert_eq!(copy_from_user(&mut buf, kernel), Err(Errno::Fault));
+        assert_eq!(copy_to_user(kernel, &buf), Err(Errno::Fault));
+        assert_eq!(copy_from_user(&mut buf, USER_END - 8), Err(Errno::Fault));
+        assert_eq!(copy_from_user(&mut [], USER_END), Ok(()));
+    }
+
+    #[test]
+    fn round_trips_through_user_page() {
+        with_user_page(TEST_PAGE, PageTableFlags::WRITABLE, || {
+            let mut buf = [0; 5];
+
+            assert_eq!(copy_to_user(TEST_PAGE + 100, b"hello"), Ok(()));
+            assert_eq!(copy_from_user(&mut buf, TEST_PAGE + 100), Ok(()));
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn rejects_writes_to_read_only_page() {
+        with_user_page(TEST_PAGE, PageTableFlags::empty(), || {
+            let mut buf = [1; 8];
+
+            assert_eq!(copy_to_user(TEST_PAGE, &buf), Err(Errno::Fault));
+            assert_eq!(copy_from_user(&mut buf, TEST_PAGE), Ok(()));
+        });
+    }
+
+    #[test]
+    fn recovers_from_fault_during_copy() {
+        // Skip the checks to copy past the end of the page, as if the next one had been unmapped
+        // after they passed.
+        with_user_page(TEST_PAGE, PageTableFlags::WRITABLE, || {
+            let buf = [0; 32];
+            let dst = (TEST_PAGE + PAGE_SIZE - 16) as *mut u8;
+
+            // SAFETY: the first 16 bytes are mapped and the rest faults.
+            let left = with_user_access(|| unsafe { copy_user(dst, buf.as_ptr(), buf.len()) });
+
+            assert_eq!(left, 16);
+        });
+    }
+}
@@ -1,12 +1,13 @@
 //! Virtual memory manager implemented using a free list.
 
 use super::{paging, pmm, PhysToVirt};
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
 use alloc::boxed::Box;
 use core::cell::Cell;
 use intrusive_collections::{
     intrusive_adapter, linked_list::CursorMut, LinkedList, LinkedListLink,
 };
-use spin::{Mutex, MutexGuard, Once};
+use spin::Once;
 use x86_64::{
     registers::control::Cr3,
     structures::paging::{
@@ -191,18 +192,18 @@ impl VMAlloc {
     }
 }
 
-static VMALLOC: Once<Mutex<VMAlloc>> = Once::new();
+static VMALLOC: Once<IrqSpinLock<VMAlloc>> = Once::new();
 
 /// Get a handle to the virtual memory manager.
 ///
 /// # Panics
 ///
 /// This function will panic if the virtual memory manager has not been initialized.
-pub(super) fn get_vmalloc() -> MutexGuard<'static, VMAlloc> {
+pub(super) fn get_vmalloc() -> IrqSpinLockGuard<'static, VMAlloc> {
     VMALLOC.get().expect("vmalloc not initialized ").lock()
 }
 
 /// Initialize the virtual memory manager.
 pub(super) fn init() {
-    VMALLOC.call_once(|| Mutex::new(VMAlloc::new()));
+    VMALLOC.call_once(|| IrqSpinLock::new(VMAlloc::new()));
 }
@@ -0,0 +1,114 @@
+//! Per-CPU data.
+//!
+//! Every CPU has a block describing it, which `GS` points to while the CPU runs kernel code. User
+//! code has its own `GS` base, which the interrupt trampolines swap in and out with `swapgs`.
+
+use crate::smp::MAX_CPUS;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Once;
+use x86_64::{
+    registers::model_specific::{GsBase, KernelGsBase},
+    VirtAddr,
+};
+
+/// The block `GS` points to. Fields are read relative to `GS` by offset, so their order must
+/// not change.
+#[repr(C)]
+struct CpuBlock {
+    /// The number of the CPU, at offset 0.
+    cpu: AtomicUsize,
+
+    /// The address of the block itself, at offset 8, so that it can be found without reading
+    /// the `GS` base MSR.
+    this: AtomicU64,
+
+    /// The user stack pointer, saved at offset 16 on system call entry.
+    user_rsp: AtomicU64,
+
+    /// The stack system calls switch to, at offset 24.
+    kernel_stack: AtomicU64,
+}
+
+impl CpuBlock {
+    const fn new() -> Self {
+        Self {
+            cpu: AtomicUsize::new(0),
+            this: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+        }
+    }
+}
+
+// Only used to initialize `BLOCKS`.
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_BLOCK: CpuBlock = CpuBlock::new();
+
+static BLOCKS: [CpuBlock; MAX_CPUS] = [CPU_BLOCK; MAX_CPUS];
+
+/// A value with a separate instance for every CPU, created on first use by each CPU.
+pub struct PerCpu<T> {
+    values: [Once<T>; MAX_CPUS],
+    init: fn() -> T,
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            values: [Once::INIT; MAX_CPUS],
+            init,
+        }
+    }
+
+    /// Return the instance of the current CPU.
+    pub fn get(&self) -> &T {
+        self.get_for(current_cpu())
+    }
+
+    /// Return the instance of the CPU numbered `cpu`.
+    pub fn get_for(&self, cpu: usize) -> &T {
+        self.values[cpu].call_once(self.init)
+    }
+}
+
+/// Return the number of the current CPU.
+pub fn current_cpu() -> usize {
+    let cpu;
+
+    // SAFETY: `GS` points to the block of the current CPU, whose first field is its number.
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) cpu,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+
+    cpu
+}
+
+/// Set the stack system calls on the current CPU switch to.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    BLOCKS[current_cpu()]
+        .kernel_stack
+        .store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// Point `GS` to the block of the CPU numbered `cpu`, which must be the current CPU.
+///
+/// This must be done before anything else on every CPU, as per-CPU data is unusable until then.
+pub fn init(cpu: usize) {
+    let block = &BLOCKS[cpu];
+    let address = VirtAddr::from_ptr(block);
+
+    block.cpu.store(cpu, Ordering::Relaxed);
+    block.this.store(address.as_u64(), Ordering::Relaxed);
+
+    GsBase::write(address);
+
+    // Swapped in by `swapgs` on the first return to user mode.
+    KernelGsBase::write(VirtAddr::zero());
+}
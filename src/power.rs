@@ -0,0 +1,279 @@
+//! System shutdown and reboot.
+
+use crate::{
+    acpi::{self, aml},
+    idt::hlt,
+};
+use ::acpi::{address::GenericAddress, fadt::Fadt};
+use ::aml::AmlValue;
+use core::arch::asm;
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+/// PM1 control register: route power management events to the SCI rather than SMI.
+const PM1_CNT_SCI_EN: u64 = 1 << 0;
+
+/// PM1 control register: the sleep type to enter.
+const PM1_CNT_SLP_TYP_SHIFT: u64 = 10;
+const PM1_CNT_SLP_TYP_MASK: u64 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+
+/// PM1 control register: enter the sleep state in `SLP_TYP`.
+const PM1_CNT_SLP_EN: u64 = 1 << 13;
+
+/// Sleep control register (hardware-reduced ACPI): the sleep type to enter.
+const SLEEP_CONTROL_SLP_TYP_SHIFT: u64 = 2;
+
+/// Sleep control register (hardware-reduced ACPI): enter the sleep state in `SLP_TYP`.
+const SLEEP_CONTROL_SLP_EN: u64 = 1 << 5;
+
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_COMMAND_PORT: u16 = 0x64;
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+const PS2_COMMAND_PULSE_RESET: u8 = 0xFE;
+
+/// Parse an AML integer encoded as a `ZeroOp`, `OneOp` or `BytePrefix`/`WordPrefix` constant.
+fn parse_aml_integer(bytes: &mut impl Iterator<Item = u8>) -> Option<u16> {
+    match bytes.next()? {
+        0x00 => Some(0),
+        0x01 => Some(1),
+        0x0A => bytes.next().map(u16::from),
+        0x0B => Some(u16::from_le_bytes([bytes.next()?, bytes.next()?])),
+        _ => None,
+    }
+}
+
+/// Parse the package defining `\_S5`, which `aml` starts with, returning its first two elements.
+fn parse_s5_package(aml: &[u8]) -> Option<(u16, u16)> {
+    let mut bytes = aml.iter().copied();
+
+    // PackageOp
+    if bytes.next()? != 0x12 {
+        return None;
+    }
+
+    // The top two bits of the lead byte of a PkgLength encode how many bytes follow it.
+    let pkg_length_lead = bytes.next()?;
+    for _ in 0..pkg_length_lead >> 6 {
+        bytes.next()?;
+    }
+
+    // NumElements
+    bytes.next()?;
+
+    let slp_typ_a = parse_aml_integer(&mut bytes)?;
+    let slp_typ_b = parse_aml_integer(&mut bytes)?;
+
+    Some((slp_typ_a, slp_typ_b))
+}
+
+/// Find the `SLP_TYPa` and `SLP_TYPb` values of the `\_S5` object by scanning the AML of the
+/// DSDT for its definition. References to the name, which may come first, are skipped.
+fn scan_s5_sleep_types(dsdt: &[u8]) -> Option<(u16, u16)> {
+    dsdt.windows(4)
+        .enumerate()
+        .filter(|(_, name)| *name == b"_S5_")
+        .find_map(|(pos, _)| {
+            // The name must be the target of a `NameOp`, optionally preceded by a root prefix.
+            let is_name = match pos {
+                0 => false,
+                1 => dsdt[0] == 0x08,
+                _ => dsdt[pos - 1] == 0x08 || (dsdt[pos - 2] == 0x08 && dsdt[pos - 1] == b'\\'),
+            };
+
+            if is_name {
+                parse_s5_package(&dsdt[pos + 4..])
+            } else {
+                None
+            }
+        })
+}
+
+/// Evaluate the `\_S5` object through the AML interpreter.
+fn evaluate_s5_sleep_types() -> Option<(u16, u16)> {
+    let AmlValue::Package(values) = aml::evaluate("\\_S5", aml::Args::EMPTY)? else {
+        return None;
+    };
+
+    match values.as_slice() {
+        [AmlValue::Integer(a), AmlValue::Integer(b), ..] => Some((*a as u16, *b as u16)),
+        _ => None,
+    }
+}
+
+/// Return the `SLP_TYPa` and `SLP_TYPb` values for the S5 (soft off) sleep state.
+///
+/// The DSDT is scanned directly if the AML interpreter is unable to evaluate `\_S5`.
+fn s5_sleep_types() -> Option<(u16, u16)> {
+    evaluate_s5_sleep_types().or_else(|| {
+        let dsdt = acpi::tables()?.dsdt().ok()?;
+        scan_s5_sleep_types(acpi::aml_stream(&dsdt))
+    })
+}
+
+/// Transfer ownership of the ACPI hardware registers from the firmware to the OS, if the
+/// firmware still owns them.
+fn enable_acpi_mode(fadt: &Fadt) {
+    let Ok(pm1a_control) = fadt.pm1a_control_block() else {
+        return;
+    };
+
+    let sci_enabled = |control| {
+        acpi::read_register(control)
+            .map(|value| value & PM1_CNT_SCI_EN != 0)
+            .unwrap_or(true)
+    };
+
+    let smi_cmd_port = { fadt.smi_cmd_port };
+    let acpi_enable = { fadt.acpi_enable };
+
+    if sci_enabled(&pm1a_control) || smi_cmd_port == 0 || acpi_enable == 0 {
+        return;
+    }
+
+    // SAFETY: the SMI command port is described by the FADT.
+    unsafe {
+        Port::<u8>::new(smi_cmd_port as u16).write(acpi_enable);
+    }
+
+    for _ in 0..1_000_000 {
+        if sci_enabled(&pm1a_control) {
+            return;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    log::warn!("power: firmware did not hand over ACPI mode");
+}
+
+/// Write a sleep type to a PM1 control register and set `SLP_EN`.
+fn write_sleep_type(control: &GenericAddress, slp_typ: u16) -> Option<()> {
+    let value = acpi::read_register(control)?;
+    let value = (value & !PM1_CNT_SLP_TYP_MASK)
+        | ((slp_typ as u64) << PM1_CNT_SLP_TYP_SHIFT)
+        | PM1_CNT_SLP_EN;
+
+    acpi::write_register(control, value)
+}
+
+/// Enter the S5 sleep state through the FADT.
+fn acpi_shutdown() -> Option<()> {
+    let tables = acpi::tables()?;
+    let fadt = tables.find_table::<Fadt>().ok()?;
+    let (slp_typ_a, slp_typ_b) = s5_sleep_types()?;
+
+    if { fadt.flags }.system_is_hw_reduced_acpi() {
+        let sleep_control = fadt.sleep_control_register().ok()??;
+        let value = ((slp_typ_a as u64) << SLEEP_CONTROL_SLP_TYP_SHIFT) | SLEEP_CONTROL_SLP_EN;
+
+        return acpi::write_register(&sleep_control, value);
+    }
+
+    enable_acpi_mode(&fadt);
+
+    // Both control blocks must be written for the sleep state to be entered.
+    if let Ok(Some(pm1b_control)) = fadt.pm1b_control_block() {
+        write_sleep_type(&pm1b_control, slp_typ_b)?;
+    }
+
+    write_sleep_type(&fadt.pm1a_control_block().ok()?, slp_typ_a)
+}
+
+/// Reset the system through the FADT reset register.
+fn acpi_reboot() -> Option<()> {
+    let tables = acpi::tables()?;
+    let fadt = tables.find_table::<Fadt>().ok()?;
+
+    if !{ fadt.flags }.supports_system_reset_via_fadt() {
+        return None;
+    }
+
+    acpi::write_register(&fadt.reset_register().ok()?, fadt.reset_value as u64)
+}
+
+/// Reset the system by pulsing the reset line of the 8042 keyboard controller.
+fn ps2_reboot() {
+    let mut status = Port::<u8>::new(PS2_STATUS_PORT);
+    let mut command = Port::<u8>::new(PS2_COMMAND_PORT);
+
+    // SAFETY: these are the standard 8042 controller ports.
+    unsafe {
+        for _ in 0..100_000 {
+            if status.read() & PS2_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        command.write(PS2_COMMAND_PULSE_RESET);
+    }
+}
+
+/// Reset the system by triple faulting: with an empty IDT, any exception escalates to a
+/// double fault and then a triple fault.
+fn triple_fault() -> ! {
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+
+    // SAFETY: resetting the system is the intended effect.
+    unsafe {
+        lidt(&idt);
+        asm!("int3", options(noreturn));
+    }
+}
+
+/// Power off the system.
+///
+/// If ACPI shutdown is unavailable or fails, the CPU is halted instead.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    log::info!("shutting down");
+
+    if acpi_shutdown().is_none() {
+        log::error!("power: ACPI shutdown unavailable");
+    }
+
+    log::error!("power: shutdown failed, halting");
+    hlt()
+}
+
+/// Reboot the system.
+///
+/// The FADT reset register is tried first, followed by the 8042 keyboard controller and
+/// finally a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    log::info!("rebooting");
+
+    if acpi_reboot().is_none() {
+        log::warn!("power: ACPI reset unavailable");
+    }
+
+    ps2_reboot();
+    log::warn!("power: 8042 reset failed, triple faulting");
+
+    triple_fault()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_past_references_to_s5() {
+        // A reference to `_S5_`, then `Name (\_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })`.
+        let dsdt = [
+            0x70, b'_', b'S', b'5', b'_', 0x60, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0A,
+            0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00,
+        ];
+
+        assert_eq!(scan_s5_sleep_types(&dsdt), Some((5, 5)));
+        assert_eq!(scan_s5_sleep_types(&dsdt[..12]), None);
+    }
+}
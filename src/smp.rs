@@ -0,0 +1,147 @@
+//! Symmetric multiprocessing: bringing up the application processors (APs) Limine parks at boot.
+//!
+//! CPUs are numbered from 0, the BSP, in the order Limine lists them.
+
+use crate::{apic, cpu, gdt, idt, mce, percpu, syscall, time, watchdog};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use limine::{SmpInfo, SmpResponse};
+
+/// The maximum amount of CPUs brought up. Further CPUs are left parked.
+pub const MAX_CPUS: usize = 16;
+
+/// How long the BSP waits for the APs to come online.
+const AP_STARTUP_TIMEOUT_MS: u64 = 1000;
+
+/// A bitmap of the CPUs that are online.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// The amount of CPUs that were started, including the BSP.
+static STARTED: AtomicUsize = AtomicUsize::new(1);
+
+/// The amount of CPUs the bootloader reported, including the BSP and those left parked.
+static REPORTED: AtomicUsize = AtomicUsize::new(1);
+
+/// Return the amount of CPUs that were started, including the BSP.
+pub fn cpu_count() -> usize {
+    STARTED.load(Ordering::Relaxed)
+}
+
+/// Return the amount of CPUs the bootloader reported, including any left parked.
+pub fn reported_count() -> usize {
+    REPORTED.load(Ordering::Relaxed)
+}
+
+/// Return the amount of CPUs that are online.
+pub fn online_count() -> usize {
+    ONLINE.load(Ordering::Acquire).count_ones() as usize
+}
+
+/// Check if the CPU numbered `cpu` is online.
+pub fn is_online(cpu: usize) -> bool {
+    cpu < MAX_CPUS && ONLINE.load(Ordering::Acquire) & (1 << cpu) != 0
+}
+
+fn set_online(cpu: usize) {
+    ONLINE.fetch_or(1 << cpu, Ordering::Release);
+}
+
+/// The entry point of the APs, called by Limine on its own stack with interrupts disabled.
+extern "C" fn ap_entry(info: *const SmpInfo) -> ! {
+    // SAFETY: Limine passes the structure describing the current CPU.
+    let info = unsafe { &*info };
+    let cpu = info.extra_argument as usize;
+
+    percpu::init(cpu);
+    gdt::init();
+    cpu::init();
+    syscall::init();
+    idt::init_ap();
+    mce::init();
+    apic::init_ap(info.processor_id);
+    time::clockevent::init_ap();
+    watchdog::init_ap();
+
+    log::info!("CPU {cpu} online (local APIC {})", info.lapic_id);
+    set_online(cpu);
+
+    crate::idle()
+}
+
+/// Start the APs described by `response` and wait for them to come online.
+///
+/// This must be called after the BSP has set up its own tables, the APICs and timekeeping.
+pub fn init(response: Option<&mut SmpResponse>) {
+    let Some(response) = response else {
+        set_online(0);
+        log::warn!("no SMP information from the bootloader, running on the BSP only");
+        return;
+    };
+
+    let bsp_lapic_id = response.bsp_lapic_id;
+    REPORTED.store(response.cpu_count as usize, Ordering::Relaxed);
+
+    log::info!("CPU 0 online (local APIC {bsp_lapic_id})");
+    set_online(0);
+
+    let mut started = 1;
+
+    for info in response.cpus() {
+        if info.lapic_id == bsp_lapic_id {
+            continue;
+        }
+
+        if started == MAX_CPUS {
+            log::warn!(
+                "leaving CPU with local APIC {} parked, at most {MAX_CPUS} CPUs are supported",
+                info.lapic_id
+            );
+            continue;
+        }
+
+        info.extra_argument = started as u64;
+
+        // SAFETY: `goto_address` is a pointer-sized field that Limine polls, and `ap_entry`
+        // never returns. The release store orders the write to `extra_argument` before it.
+        unsafe {
+            let goto_address = &*(&info.goto_address as *const _ as *const AtomicU64);
+            goto_address.store(ap_entry as *const () as u64, Ordering::Release);
+        }
+
+        started += 1;
+    }
+
+    STARTED.store(started, Ordering::Relaxed);
+
+    for _ in 0..AP_STARTUP_TIMEOUT_MS {
+        if online_count() == started {
+            break;
+        }
+
+        time::mdelay(1);
+    }
+
+    let online = online_count();
+    if online < started {
+        log::warn!("only {online} of {started} CPU(s) came online");
+    }
+
+    log::info!("{online} CPU(s) online");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_cpus_online() {
+        if reported_count() > 1 {
+            assert!(cpu_count() > 1, "no AP was started");
+        }
+
+        for cpu in 0..cpu_count() {
+            assert!(is_online(cpu), "CPU {cpu} did not check in");
+        }
+
+        assert_eq!(online_count(), cpu_count());
+    }
+}
@@ -0,0 +1,306 @@
+//! Interrupt-safe locks.
+//!
+//! A plain spinlock taken by both an interrupt handler and the code it interrupted deadlocks the
+//! CPU. These locks disable interrupts while held, and restore the previous interrupt state once
+//! released, so that they nest.
+//!
+//! With the `lockdep` feature, the order they are acquired in is validated by [`lockdep`].
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+
+use core::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use x86_64::instructions::interrupts;
+
+/// Disable interrupts, returning whether they were enabled.
+fn save_and_disable() -> bool {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    enabled
+}
+
+fn restore(enabled: bool) {
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+/// Return the address of `lock`, which identifies it to [`lockdep`].
+#[cfg(feature = "lockdep")]
+fn address<T: ?Sized>(lock: &T) -> usize {
+    lock as *const T as *const () as usize
+}
+
+/// A spinlock that disables interrupts while held.
+pub struct IrqSpinLock<T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    class: lockdep::Class,
+
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(feature = "lockdep")]
+            class: lockdep::Class::new(),
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    /// Disable interrupts and spin until the lock is acquired.
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let interrupts_enabled = save_and_disable();
+
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, address(self), lockdep::Acquire::Exclusive);
+
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+            #[cfg(feature = "lockdep")]
+            lock: address(self),
+        }
+    }
+
+    /// Acquire the lock if it is free, leaving interrupts untouched otherwise.
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let interrupts_enabled = save_and_disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lockdep")]
+                lockdep::acquire(&self.class, address(self), lockdep::Acquire::Try);
+
+                Some(IrqSpinLockGuard {
+                    guard: ManuallyDrop::new(guard),
+                    interrupts_enabled,
+                    #[cfg(feature = "lockdep")]
+                    lock: address(self),
+                })
+            }
+            None => {
+                restore(interrupts_enabled);
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Spin for the lock at most `spins` times, then take it away from its holder.
+    ///
+    /// This is for locks protecting output used from contexts that disabling interrupts does not
+    /// keep out, such as NMIs, which may have interrupted the holder on the same CPU or found it
+    /// stuck on another. The output of the holder may be interleaved with that of the caller.
+    pub fn lock_or_bust(&self, spins: usize) -> IrqSpinLockGuard<'_, T> {
+        for _ in 0..spins {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        // SAFETY: the lock only protects output, which the holder is done with or cannot finish.
+        unsafe { self.force_unlock() };
+
+        // Keep trying rather than waiting, as the holder may be the interrupted context on this
+        // CPU, which is not recursion for the lock order validator to report.
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Release the lock without a guard, leaving interrupts untouched.
+    ///
+    /// # Safety
+    ///
+    /// The holder of the lock must never use it again, such as when it will never run again
+    /// after a panic.
+    pub unsafe fn force_unlock(&self) {
+        // SAFETY: guaranteed by the caller.
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+
+    #[cfg(feature = "lockdep")]
+    lock: usize,
+}
+
+impl<T: ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: the guard is not used after this.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock);
+
+        restore(self.interrupts_enabled);
+    }
+}
+
+/// A reader-writer spinlock that disables interrupts while held.
+pub struct IrqRwLock<T: ?Sized> {
+    #[cfg(feature = "lockdep")]
+    class: lockdep::Class,
+
+    inner: RwLock<T>,
+}
+
+impl<T> IrqRwLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(feature = "lockdep")]
+            class: lockdep::Class::new(),
+            inner: RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqRwLock<T> {
+    /// Disable interrupts and spin until there is no writer.
+    pub fn read(&self) -> IrqRwLockReadGuard<'_, T> {
+        let interrupts_enabled = save_and_disable();
+
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, address(self), lockdep::Acquire::Shared);
+
+        IrqRwLockReadGuard {
+            guard: ManuallyDrop::new(self.inner.read()),
+            interrupts_enabled,
+            #[cfg(feature = "lockdep")]
+            lock: address(self),
+        }
+    }
+
+    /// Disable interrupts and spin until there are no readers or writer.
+    pub fn write(&self) -> IrqRwLockWriteGuard<'_, T> {
+        let interrupts_enabled = save_and_disable();
+
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, address(self), lockdep::Acquire::Exclusive);
+
+        IrqRwLockWriteGuard {
+            guard: ManuallyDrop::new(self.inner.write()),
+            interrupts_enabled,
+            #[cfg(feature = "lockdep")]
+            lock: address(self),
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+pub struct IrqRwLockReadGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<RwLockReadGuard<'a, T>>,
+    interrupts_enabled: bool,
+
+    #[cfg(feature = "lockdep")]
+    lock: usize,
+}
+
+impl<T: ?Sized> Deref for IrqRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: the guard is not used after this.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock);
+
+        restore(self.interrupts_enabled);
+    }
+}
+
+pub struct IrqRwLockWriteGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<RwLockWriteGuard<'a, T>>,
+    interrupts_enabled: bool,
+
+    #[cfg(feature = "lockdep")]
+    lock: usize,
+}
+
+impl<T: ?Sized> Deref for IrqRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: the guard is not used after this.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock);
+
+        restore(self.interrupts_enabled);
+    }
+}
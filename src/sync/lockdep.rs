@@ -0,0 +1,538 @@
+//! A lock order validator for the locks in [`super`], enabled by the `lockdep` feature.
+//!
+//! Every lock belongs to a class named after the place it is created at, so all the locks created
+//! by the same constructor share a class. Acquiring a lock while others are held records the
+//! order as edges in a graph of classes. If the class being acquired can already reach a held
+//! class, two CPUs taking the locks along both paths can deadlock, even if they never have; if
+//! it is held already, the CPU deadlocks on its own. The first such problem is reported with the
+//! stacks involved, after which validation is turned off as the graph is no longer trustworthy.
+
+use crate::{
+    backtrace,
+    percpu::{self, PerCpu},
+};
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+/// The maximum amount of classes, one for each bit of [`Graph::after`].
+const MAX_CLASSES: usize = 64;
+
+const MAX_EDGES: usize = 256;
+
+/// The maximum amount of locks a CPU holds at once.
+const MAX_HELD: usize = 16;
+
+/// The amount of dependencies shown when reporting a circular dependency.
+const MAX_CHAIN: usize = 8;
+
+/// The amount of frames recorded per stack, including those of the validator itself.
+const STACK_DEPTH: usize = 12;
+
+type Stack = [u64; STACK_DEPTH];
+
+fn capture() -> Stack {
+    let mut stack = [0; STACK_DEPTH];
+    backtrace::capture(&mut stack);
+    stack
+}
+
+fn print_stack(stack: &Stack) {
+    let len = stack
+        .iter()
+        .position(|&addr| addr == 0)
+        .unwrap_or(STACK_DEPTH);
+    backtrace::print_captured(&stack[..len]);
+}
+
+/// The class of a lock, named after the place the lock is created at.
+pub struct Class {
+    location: &'static Location<'static>,
+
+    /// The index of the class in the graph plus one, or 0 until it has been looked up.
+    id: AtomicUsize,
+}
+
+impl Class {
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            location: Location::caller(),
+            id: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// How a lock is acquired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Acquire {
+    Exclusive,
+
+    /// Shared with other readers, which may nest.
+    Shared,
+
+    /// Without waiting, which cannot deadlock, so nothing is validated.
+    Try,
+}
+
+/// A class acquired while holding another, and the stack it was first acquired at.
+#[derive(Clone, Copy)]
+struct Edge {
+    from: usize,
+    to: usize,
+    stack: Stack,
+}
+
+impl Edge {
+    const EMPTY: Self = Self {
+        from: 0,
+        to: 0,
+        stack: [0; STACK_DEPTH],
+    };
+}
+
+/// An edge along a circular dependency, resolved to the names of its classes.
+#[derive(Clone, Copy)]
+struct Link {
+    from: &'static Location<'static>,
+    to: &'static Location<'static>,
+    stack: Stack,
+}
+
+struct Graph {
+    classes: [Option<&'static Location<'static>>; MAX_CLASSES],
+
+    /// `after[a]` has bit `b` set if class `b` has been acquired while holding class `a`.
+    after: [u64; MAX_CLASSES],
+
+    edges: [Edge; MAX_EDGES],
+    edge_count: usize,
+}
+
+impl Graph {
+    const fn new() -> Self {
+        Self {
+            classes: [None; MAX_CLASSES],
+            after: [0; MAX_CLASSES],
+            edges: [Edge::EMPTY; MAX_EDGES],
+            edge_count: 0,
+        }
+    }
+
+    /// Return the index of `class`, adding it if it is new. Returns `None` if there is no room.
+    fn class_id(&mut self, class: &Class) -> Option<usize> {
+        if let Some(id) = class.id.load(Ordering::Relaxed).checked_sub(1) {
+            return Some(id);
+        }
+
+        let id = match self.classes.iter().position(|&c| c == Some(class.location)) {
+            Some(id) => id,
+            None => {
+                let id = self.classes.iter().position(Option::is_none)?;
+                self.classes[id] = Some(class.location);
+                id
+            }
+        };
+
+        class.id.store(id + 1, Ordering::Relaxed);
+        Some(id)
+    }
+
+    fn has_edge(&self, from: usize, to: usize) -> bool {
+        self.after[from] & (1 << to) != 0
+    }
+
+    /// Record that `to` was acquired while holding `from`. Returns `false` if there is no room.
+    fn add_edge(&mut self, from: usize, to: usize, stack: Stack) -> bool {
+        let Some(edge) = self.edges.get_mut(self.edge_count) else {
+            return false;
+        };
+
+        *edge = Edge { from, to, stack };
+        self.edge_count += 1;
+        self.after[from] |= 1 << to;
+
+        true
+    }
+
+    /// Return the edges along the shortest path from `from` to `to`, if there is one.
+    fn chain(&self, from: usize, to: usize) -> Option<[Option<Link>; MAX_CHAIN]> {
+        // Breadth-first search, recording the class each one was reached from.
+        let mut parent = [usize::MAX; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+
+        queue[0] = from;
+        parent[from] = from;
+
+        while head < tail && parent[to] == usize::MAX {
+            let class = queue[head];
+            head += 1;
+
+            let mut next = self.after[class];
+            while next != 0 {
+                let reached = next.trailing_zeros() as usize;
+                next &= next - 1;
+
+                if parent[reached] == usize::MAX {
+                    parent[reached] = class;
+                    queue[tail] = reached;
+                    tail += 1;
+                }
+            }
+        }
+
+        if parent[to] == usize::MAX {
+            return None;
+        }
+
+        // Walk back from `to`, then keep the links closest to `from`.
+        let mut path = [0; MAX_CLASSES];
+        let mut len = 0;
+        let mut class = to;
+
+        while class != from {
+            path[len] = class;
+            len += 1;
+            class = parent[class];
+        }
+
+        path[len] = from;
+        len += 1;
+        path[..len].reverse();
+
+        let mut chain = [None; MAX_CHAIN];
+        for (link, pair) in chain.iter_mut().zip(path[..len].windows(2)) {
+            let edge = self.edges[..self.edge_count]
+                .iter()
+                .find(|edge| edge.from == pair[0] && edge.to == pair[1])?;
+
+            *link = Some(Link {
+                from: self.classes[edge.from]?,
+                to: self.classes[edge.to]?,
+                stack: edge.stack,
+            });
+        }
+
+        Some(chain)
+    }
+
+    /// Check acquiring class `id` while holding `held`, recording the new dependencies if it
+    /// cannot deadlock.
+    fn validate(
+        &mut self,
+        held: &HeldLocks,
+        id: usize,
+        shared: bool,
+        stack: Stack,
+    ) -> Result<(), Violation> {
+        for prev in held.iter() {
+            if prev.class == id {
+                if prev.shared && shared {
+                    continue;
+                }
+
+                return Err(Violation::Recursive(*prev));
+            }
+
+            if self.has_edge(prev.class, id) {
+                continue;
+            }
+
+            if let Some(chain) = self.chain(id, prev.class) {
+                return Err(Violation::Circular(*prev, chain));
+            }
+
+            if !self.add_edge(prev.class, id, stack) {
+                return Err(Violation::TooManyDependencies);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: usize,
+    location: &'static Location<'static>,
+
+    /// The address of the lock, to tell it apart from other locks of its class.
+    lock: usize,
+
+    shared: bool,
+    stack: Stack,
+}
+
+/// Why acquiring a lock may deadlock, or cannot be validated.
+enum Violation {
+    /// The class is held already, by the lock given.
+    Recursive(Held),
+
+    /// The class can reach the class of the lock given through the chain of dependencies.
+    Circular(Held, [Option<Link>; MAX_CHAIN]),
+
+    /// There is no room for the new dependencies.
+    TooManyDependencies,
+}
+
+/// The locks held by a CPU, in acquisition order.
+struct HeldLocks {
+    locks: [Option<Held>; MAX_HELD],
+    depth: usize,
+}
+
+impl HeldLocks {
+    const fn new() -> Self {
+        Self {
+            locks: [None; MAX_HELD],
+            depth: 0,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Held> {
+        self.locks[..self.depth].iter().flatten()
+    }
+
+    fn push(&mut self, held: Held) -> bool {
+        let Some(slot) = self.locks.get_mut(self.depth) else {
+            return false;
+        };
+
+        *slot = Some(held);
+        self.depth += 1;
+
+        true
+    }
+
+    /// Remove the last acquisition of `lock`. Locks may be released in any order.
+    fn remove(&mut self, lock: usize) {
+        let Some(index) = self.locks[..self.depth]
+            .iter()
+            .rposition(|held| held.is_some_and(|held| held.lock == lock))
+        else {
+            // Acquired before validation was enabled.
+            return;
+        };
+
+        self.locks[index..self.depth].rotate_left(1);
+        self.depth -= 1;
+        self.locks[self.depth] = None;
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static GRAPH: Mutex<Graph> = Mutex::new(Graph::new());
+
+/// The locks held by each CPU. Locks acquired while the CPU is already in the validator, such as
+/// by the logger while reporting, find these locked and are not tracked.
+static HELD: PerCpu<Mutex<HeldLocks>> = PerCpu::new(|| Mutex::new(HeldLocks::new()));
+
+/// Stop validating, returning `false` if validation was already turned off.
+fn turn_off() -> bool {
+    ENABLED.swap(false, Ordering::Relaxed)
+}
+
+fn give_up(reason: &str) {
+    if turn_off() {
+        log::warn!("lockdep: {reason}, turning off lock validation");
+    }
+}
+
+fn report_recursive(held: &Held, stack: &Stack) {
+    if !turn_off() {
+        return;
+    }
+
+    log::error!(
+        "lockdep: possible recursive locking on CPU {}",
+        percpu::current_cpu()
+    );
+    log::error!(
+        "lockdep: acquiring {} while holding it, acquired at:",
+        held.location
+    );
+    print_stack(&held.stack);
+    log::error!("lockdep: current stack:");
+    print_stack(stack);
+    log::error!("lockdep: turning off lock validation");
+}
+
+fn report_circular(
+    held: &Held,
+    location: &Location<'_>,
+    stack: &Stack,
+    chain: &[Option<Link>; MAX_CHAIN],
+) {
+    if !turn_off() {
+        return;
+    }
+
+    log::error!(
+        "lockdep: possible circular locking dependency on CPU {}",
+        percpu::current_cpu()
+    );
+    log::error!(
+        "lockdep: acquiring {location} while holding {}, acquired at:",
+        held.location
+    );
+    print_stack(&held.stack);
+    log::error!("lockdep: current stack:");
+    print_stack(stack);
+    log::error!("lockdep: existing dependency chain:");
+
+    for link in chain.iter().flatten() {
+        log::error!(
+            "lockdep: {} acquired while holding {} at:",
+            link.to,
+            link.from
+        );
+        print_stack(&link.stack);
+    }
+
+    log::error!("lockdep: turning off lock validation");
+}
+
+/// Validate and record the acquisition of the lock at address `lock`, before waiting for it.
+///
+/// This must be called with interrupts disabled.
+pub(super) fn acquire(class: &Class, lock: usize, how: Acquire) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let Some(mut held) = HELD.get().try_lock() else {
+        return;
+    };
+
+    let stack = capture();
+    let mut graph = GRAPH.lock();
+
+    let Some(id) = graph.class_id(class) else {
+        drop(graph);
+        give_up("too many lock classes");
+        return;
+    };
+
+    let shared = how == Acquire::Shared;
+
+    if how != Acquire::Try {
+        if let Err(violation) = graph.validate(&held, id, shared, stack) {
+            drop(graph);
+
+            match violation {
+                Violation::Recursive(prev) => report_recursive(&prev, &stack),
+                Violation::Circular(prev, chain) => {
+                    report_circular(&prev, class.location, &stack, &chain)
+                }
+                Violation::TooManyDependencies => give_up("too many lock dependencies"),
+            }
+
+            return;
+        }
+    }
+
+    drop(graph);
+
+    let pushed = held.push(Held {
+        class: id,
+        location: class.location,
+        lock,
+        shared,
+        stack,
+    });
+
+    if !pushed {
+        give_up("too many locks held");
+    }
+}
+
+/// Record the release of the lock at address `lock`.
+pub(super) fn release(lock: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(mut held) = HELD.get().try_lock() {
+        held.remove(lock);
+    }
+}
+
+/// Start validating locks. Per-CPU data must be usable on any CPU acquiring locks from then on.
+pub fn init() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[cfg(all(test, feature = "lockdep"))]
+mod tests {
+    use super::*;
+
+    const NO_STACK: Stack = [0; STACK_DEPTH];
+
+    /// Return the locks held after acquiring `locks`, each a class and whether it is shared.
+    fn holding(graph: &Graph, locks: &[(usize, bool)]) -> HeldLocks {
+        let mut held = HeldLocks::new();
+
+        for (lock, &(class, shared)) in locks.iter().enumerate() {
+            assert!(held.push(Held {
+                class,
+                location: graph.classes[class].unwrap(),
+                lock,
+                shared,
+                stack: NO_STACK,
+            }));
+        }
+
+        held
+    }
+
+    #[test]
+    fn finds_inversion() {
+        let (a, b) = (Class::new(), Class::new());
+        let mut graph = Graph::new();
+        let a = graph.class_id(&a).unwrap();
+        let b = graph.class_id(&b).unwrap();
+
+        assert!(graph.chain(b, a).is_none());
+        assert!(graph
+            .validate(&holding(&graph, &[(a, false)]), b, false, NO_STACK)
+            .is_ok());
+        assert!(graph.has_edge(a, b));
+
+        let chain = graph.chain(a, b).unwrap();
+        assert!(chain[0].is_some_and(|link| link.from == graph.classes[a].unwrap()));
+        assert!(chain[1].is_none());
+
+        let violation = graph.validate(&holding(&graph, &[(b, false)]), a, false, NO_STACK);
+        assert!(matches!(violation, Err(Violation::Circular(prev, _)) if prev.class == b));
+    }
+
+    #[test]
+    fn finds_recursion() {
+        let a = Class::new();
+        let mut graph = Graph::new();
+        let a = graph.class_id(&a).unwrap();
+
+        let violation = graph.validate(&holding(&graph, &[(a, false)]), a, false, NO_STACK);
+        assert!(matches!(violation, Err(Violation::Recursive(prev)) if prev.class == a));
+
+        // A reader nested in a writer deadlocks all the same.
+        let violation = graph.validate(&holding(&graph, &[(a, false)]), a, true, NO_STACK);
+        assert!(matches!(violation, Err(Violation::Recursive(_))));
+    }
+
+    #[test]
+    fn allows_nested_readers() {
+        let a = Class::new();
+        let mut graph = Graph::new();
+        let a = graph.class_id(&a).unwrap();
+
+        assert!(graph
+            .validate(&holding(&graph, &[(a, true)]), a, true, NO_STACK)
+            .is_ok());
+        assert!(!graph.has_edge(a, a));
+    }
+}
@@ -0,0 +1,311 @@
+//! System calls, entered through `SYSCALL` or, for debugging, `int 0x80`.
+//!
+//! The system call number is passed in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`,
+//! `r10`, `r8` and `r9`. The result is returned in `rax`, with errors as negated [`Errno`]
+//! values.
+
+use crate::{gdt, idt::TrapFrame, percpu, time};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+/// The vector of the `int 0x80` entry point.
+pub const VECTOR: u8 = 0x80;
+
+/// Errors returned by system calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Errno {
+    /// Bad address.
+    Fault = 14,
+
+    /// Invalid argument.
+    Inval = 22,
+
+    /// No such system call.
+    NoSys = 38,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// The arguments of a system call.
+pub type Args = [u64; 6];
+
+type Handler = fn(&Args) -> SyscallResult;
+
+/// A system call handler and the name it is logged under.
+struct Syscall {
+    name: &'static str,
+    handler: Handler,
+}
+
+/// System call numbers.
+pub mod nr {
+    pub const UPTIME: u64 = 0;
+    pub const REALTIME: u64 = 1;
+    pub const GETCPU: u64 = 2;
+}
+
+/// The system call handlers, indexed by number.
+static TABLE: &[Syscall] = &[
+    Syscall {
+        name: "uptime",
+        handler: sys_uptime,
+    },
+    Syscall {
+        name: "realtime",
+        handler: sys_realtime,
+    },
+    Syscall {
+        name: "getcpu",
+        handler: sys_getcpu,
+    },
+];
+
+/// Return the nanoseconds elapsed since boot.
+fn sys_uptime(_: &Args) -> SyscallResult {
+    Ok(time::monotonic())
+}
+
+/// Return the nanoseconds elapsed since the Unix epoch.
+fn sys_realtime(_: &Args) -> SyscallResult {
+    Ok(time::realtime())
+}
+
+/// Return the number of the CPU the caller runs on.
+fn sys_getcpu(_: &Args) -> SyscallResult {
+    Ok(percpu::current_cpu() as u64)
+}
+
+/// Run system call `number`, returning its result as passed back in `rax`.
+fn dispatch(number: u64, args: &Args) -> u64 {
+    let result = match usize::try_from(number).ok().and_then(|n| TABLE.get(n)) {
+        Some(syscall) => {
+            log::trace!("syscall {}({:#X?})", syscall.name, args);
+            (syscall.handler)(args)
+        }
+        None => Err(Errno::NoSys),
+    };
+
+    match result {
+        Ok(value) => value,
+        Err(errno) => (errno as u64).wrapping_neg(),
+    }
+}
+
+/// The registers saved by [`syscall_entry`], in the order they are laid out on the stack.
+#[derive(Debug)]
+#[repr(C)]
+struct SyscallFrame {
+    r9: u64,
+    r8: u64,
+    r10: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rax: u64,
+
+    /// The user return address, saved by the CPU in `rcx`.
+    rip: u64,
+
+    /// The user flags, saved by the CPU in `r11`.
+    rflags: u64,
+
+    rsp: u64,
+}
+
+/// The user code and stack segment selectors, for [`syscall_entry`] to return through `IRETQ`.
+static USER_CS: AtomicU64 = AtomicU64::new(0);
+static USER_SS: AtomicU64 = AtomicU64::new(0);
+
+/// Run the system call saved in `frame`. Returns `false` if the return address is not canonical,
+/// in which case `SYSRET` would fault in kernel mode, on the user stack, and the system call must
+/// return through `IRETQ` instead.
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) -> bool {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+
+    interrupts::enable();
+    frame.rax = dispatch(frame.rax, &args);
+    interrupts::disable();
+
+    VirtAddr::try_new(frame.rip).is_ok()
+}
+
+extern "C" {
+    /// The `IRETQ` instruction of [`syscall_entry`], which faults in kernel mode if the return
+    /// address is not canonical.
+    fn syscall_iret();
+}
+
+/// The `SYSCALL` entry point. The CPU arrives here with interrupts disabled, the user `GS` base
+/// and stack, the return address in `rcx` and the flags in `r11`.
+///
+/// The user stack pointer is stashed in the per-CPU block while switching to the kernel stack,
+/// after which the registers are saved as a [`SyscallFrame`]. The 10 quadwords keep the
+/// 16-byte aligned kernel stack aligned for the call.
+///
+/// Returning to a non-canonical address goes through `IRETQ`, which faults in kernel mode rather
+/// than in the user context, as `SYSRETQ` would; [`report_bad_iret`] reports it. The frame it
+/// returns through is built over the saved `rip`, `rflags` and `rsp`, 2 quadwords lower.
+#[naked]
+unsafe extern "C" fn syscall_entry() -> ! {
+    unsafe {
+        asm!(
+            "swapgs",
+            "mov gs:[16], rsp",
+            "mov rsp, gs:[24]",
+            "push qword ptr gs:[16]",
+            "push r11",
+            "push rcx",
+            "push rax",
+            "push rdi",
+            "push rsi",
+            "push rdx",
+            "push r10",
+            "push r8",
+            "push r9",
+            "mov rdi, rsp",
+            "cld",
+            "call {dispatch}",
+            "test al, al",
+            "pop r9",
+            "pop r8",
+            "pop r10",
+            "pop rdx",
+            "pop rsi",
+            "pop rdi",
+            "pop rax",
+            "jz 2f",
+            "pop rcx",
+            "pop r11",
+            "pop rsp",
+            "swapgs",
+            "sysretq",
+            "2:",
+            "sub rsp, 16",
+            "mov rcx, [rsp + 16]",
+            "mov r11, [rsp + 24]",
+            "mov [rsp], rcx",
+            "mov [rsp + 16], r11",
+            "mov rcx, [rsp + 32]",
+            "mov [rsp + 24], rcx",
+            "mov rcx, qword ptr [rip + {user_cs}]",
+            "mov [rsp + 8], rcx",
+            "mov rcx, qword ptr [rip + {user_ss}]",
+            "mov [rsp + 32], rcx",
+            // Leave `rcx` and `r11` as `SYSRET` would.
+            "mov rcx, [rsp]",
+            "swapgs",
+            ".global syscall_iret",
+            "syscall_iret:",
+            "iretq",
+            dispatch = sym syscall_dispatch,
+            user_cs = sym USER_CS,
+            user_ss = sym USER_SS,
+            options(noreturn)
+        );
+    }
+}
+
+/// Check if a fault at `rip` was raised by returning from a system call to a non-canonical
+/// address.
+pub(crate) fn is_bad_iret(rip: u64) -> bool {
+    rip == syscall_iret as *const () as u64
+}
+
+/// Report the user context the `IRETQ` of [`syscall_entry`] faulted returning to. There are no
+/// tasks to kill yet, so the fault stays fatal to the kernel.
+///
+/// The fault is taken in kernel mode with the user `GS` base, so it must enter through the
+/// paranoid entry path, which switches to the kernel one.
+pub(crate) fn report_bad_iret(frame: &TrapFrame) {
+    // SAFETY: the fault was raised by `IRETQ`, so the stack pointer points at its frame.
+    let [rip, cs, rflags, rsp, ss] = unsafe { *(frame.rsp as *const [u64; 5]) };
+
+    log::error!("system call returned to non-canonical address {rip:#X}");
+    log::error!("user context: CS={cs:#06X} SS={ss:#06X} RSP={rsp:#018X} RFLAGS={rflags:#X}");
+}
+
+/// Handle a system call made through `int 0x80`, with the same registers as `SYSCALL`.
+pub(crate) fn interrupt(frame: &mut TrapFrame) {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+
+    frame.rax = dispatch(frame.rax, &args);
+}
+
+/// Enable `SYSCALL` on the current CPU.
+pub fn init() {
+    let selectors = gdt::selectors();
+
+    Star::write(
+        selectors.ucode,
+        selectors.udata,
+        selectors.kcode,
+        selectors.kdata,
+    )
+    .expect("GDT layout incompatible with SYSRET");
+
+    USER_CS.store(u64::from(selectors.ucode.0), Ordering::Relaxed);
+    USER_SS.store(u64::from(selectors.udata.0), Ordering::Relaxed);
+
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+
+    // Enter the kernel with interrupts disabled until the stack has been switched, and with the
+    // flags the kernel expects.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK
+            | RFlags::NESTED_TASK,
+    );
+
+    // SAFETY: SYSCALL is supported by every x86-64 CPU and its MSRs have been set up above.
+    unsafe {
+        Efer::update(|efer| efer.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+
+    log::info!(
+        "enabled system calls on CPU {} with {} handler(s)",
+        percpu::current_cpu(),
+        TABLE.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make system call `number` through `int 0x80`, which the kernel may raise itself.
+    fn int80(number: u64) -> u64 {
+        let mut rax = number;
+
+        // SAFETY: the system call vector is set up by `idt::init` and clobbers nothing but `rax`.
+        unsafe { asm!("int 0x80", inout("rax") rax, options(nostack)) };
+
+        rax
+    }
+
+    #[test]
+    fn int80_dispatches_through_table() {
+        assert_eq!(int80(nr::GETCPU), 0);
+    }
+
+    #[test]
+    fn int80_rejects_unknown_numbers() {
+        assert_eq!(int80(TABLE.len() as u64) as i64, -(Errno::NoSys as i64));
+    }
+}
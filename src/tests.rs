@@ -1,3 +1,26 @@
+use crate::power;
+use x86_64::instructions::port::Port;
+
+/// The I/O port of QEMU's `isa-debug-exit` device, set up by the `test` target of the Makefile.
+const EXIT_PORT: u16 = 0xF4;
+
+/// The codes written to [`EXIT_PORT`]. QEMU exits with status `(code << 1) | 1`, which the
+/// Makefile maps to its own exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Exit QEMU with `code`, powering off if the `isa-debug-exit` device is missing.
+pub fn exit_qemu(code: ExitCode) -> ! {
+    // SAFETY: the port is unused unless QEMU's debug exit device is attached to it.
+    unsafe { Port::new(EXIT_PORT).write(code as u32) };
+
+    power::shutdown()
+}
+
 pub struct Test {
     pub path: &'static str,
     pub func: fn(),
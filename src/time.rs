@@ -0,0 +1,177 @@
+//! Timekeeping: the periodic tick, the monotonic clock and busy-wait delays.
+
+pub mod clockevent;
+pub mod clocksource;
+pub mod timer;
+
+use crate::{
+    cpu::{self, Feature},
+    drivers::{hpet, pit, rtc},
+};
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The frequency of the periodic timer interrupt, set at build time through `MONOOS_HZ`.
+pub const HZ: u64 = parse_hz(option_env!("MONOOS_HZ"));
+
+const DEFAULT_HZ: u64 = 100;
+
+pub const NS_PER_US: u64 = 1_000;
+pub const NS_PER_MS: u64 = 1_000_000;
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// How often the clock source is checked against its watchdog, one second.
+const WATCHDOG_INTERVAL_TICKS: u64 = HZ;
+
+/// How long the TSC is measured against a reference timer to calibrate it.
+const TSC_CALIBRATION_NS: u64 = 10 * NS_PER_MS;
+
+const fn parse_hz(hz: Option<&str>) -> u64 {
+    let Some(hz) = hz else {
+        return DEFAULT_HZ;
+    };
+
+    let bytes = hz.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "MONOOS_HZ must be an integer");
+        value = value * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+
+    assert!(
+        value >= 19 && value <= 10_000,
+        "MONOOS_HZ must be between 19 and 10000"
+    );
+    value
+}
+
+/// The amount of timer interrupts received since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The length of a tick in nanoseconds, as actually programmed into the clock event device.
+static TICK_NS: AtomicU64 = AtomicU64::new(NS_PER_SEC / HZ);
+
+/// The wall-clock time in nanoseconds since the Unix epoch at which the monotonic clock read 0.
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The frequency of the TSC in kHz, or 0 if it has not been calibrated.
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn rdtsc() -> u64 {
+    // SAFETY: the TSC is present on every x86-64 CPU.
+    unsafe { _rdtsc() }
+}
+
+/// Account for a tick raised by the clock event device.
+fn tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if ticks.rem_euclid(WATCHDOG_INTERVAL_TICKS) == 0 {
+        clocksource::watchdog();
+    }
+
+    timer::tick();
+}
+
+/// Return the amount of ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Return the length of a tick in nanoseconds.
+pub fn tick_ns() -> u64 {
+    TICK_NS.load(Ordering::Relaxed)
+}
+
+fn set_tick_ns(ns: u64) {
+    TICK_NS.store(ns, Ordering::Relaxed);
+}
+
+/// Return the frequency of the TSC in Hz, if it has been calibrated.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_KHZ.load(Ordering::Relaxed) {
+        0 => None,
+        khz => Some(khz * 1000),
+    }
+}
+
+/// Return the time elapsed since the clocks were started at boot, in nanoseconds.
+///
+/// This reads the highest rated stable clock source, falling back to the tick counter before
+/// one has been selected.
+pub fn monotonic() -> u64 {
+    clocksource::read_ns().unwrap_or_else(|| ticks() * tick_ns())
+}
+
+/// Return the wall-clock time in nanoseconds since the Unix epoch.
+///
+/// The RTC is read once at boot and the monotonic clock is used to advance it from there.
+pub fn realtime() -> u64 {
+    REALTIME_OFFSET.load(Ordering::Relaxed) + monotonic()
+}
+
+/// Set the wall-clock time to `ns` nanoseconds since the Unix epoch.
+pub fn set_realtime(ns: u64) {
+    REALTIME_OFFSET.store(ns.saturating_sub(monotonic()), Ordering::Relaxed);
+}
+
+/// Busy-wait for at least `ns` nanoseconds.
+pub fn ndelay(ns: u64) {
+    // The tick only advances with interrupts enabled, so it cannot be waited on.
+    if !clocksource::is_high_resolution() {
+        return pit::wait(ns);
+    }
+
+    let start = monotonic();
+    while monotonic() - start < ns {
+        core::hint::spin_loop();
+    }
+}
+
+/// Busy-wait for at least `us` microseconds.
+pub fn udelay(us: u64) {
+    ndelay(us * NS_PER_US);
+}
+
+/// Busy-wait for at least `ms` milliseconds.
+pub fn mdelay(ms: u64) {
+    ndelay(ms * NS_PER_MS);
+}
+
+/// Measure the frequency of the TSC in kHz against the HPET, or the PIT if there is no HPET.
+fn calibrate_tsc() -> u64 {
+    let start = rdtsc();
+
+    match hpet::get() {
+        Some(hpet) => hpet.wait(TSC_CALIBRATION_NS),
+        None => pit::wait(TSC_CALIBRATION_NS),
+    }
+
+    let elapsed = rdtsc() - start;
+    elapsed * NS_PER_MS / TSC_CALIBRATION_NS
+}
+
+/// Calibrate the TSC, select the clock source and the clock event device raising the tick at
+/// `HZ`, start the kernel timers, and set the wall-clock time from the RTC.
+pub fn init() {
+    // The TSC can only be used as a clock if it runs at a constant rate regardless of power state.
+    if cpu::has(Feature::InvariantTsc) {
+        let khz = calibrate_tsc();
+        TSC_KHZ.store(khz, Ordering::Relaxed);
+
+        log::info!("TSC calibrated at {}.{:03} MHz", khz / 1000, khz % 1000);
+    } else {
+        log::warn!("TSC is not invariant, falling back to a coarser clock");
+    }
+
+    clocksource::init();
+    timer::init();
+    clockevent::init();
+
+    set_realtime(rtc::read().to_unix() * NS_PER_SEC);
+}
@@ -0,0 +1,333 @@
+//! Clock event devices: programmable timers that raise the tick interrupt.
+
+use super::{tick_ns, HZ, NS_PER_SEC};
+use crate::{
+    apic::{
+        self,
+        lapic::{self, TimerMode},
+    },
+    cpu::{self, Feature},
+    drivers::{hpet, pit},
+    idt::{TrapFrame, PIC1_OFFSET},
+    irq::{self, IrqReturn},
+    percpu,
+    sync::IrqSpinLock,
+    watchdog,
+};
+use alloc::vec::Vec;
+use spin::Once;
+
+/// How long the Local APIC timer is measured to calibrate it.
+const LAPIC_CALIBRATION_NS: u64 = 10 * super::NS_PER_MS;
+
+/// A timer able to raise interrupts on a vector.
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How suitable this device is; the highest rated device is used.
+    fn rating(&self) -> u32;
+
+    /// The vector the interrupts of this device are delivered on.
+    fn vector(&self) -> u8;
+
+    /// Whether the device can fire periodically, rather than only once per programming.
+    fn is_periodic_capable(&self) -> bool;
+
+    /// Fire `hz` times per second, returning the frequency actually programmed.
+    fn set_periodic(&self, hz: u64) -> u64;
+
+    /// Fire once, `ns` nanoseconds from now.
+    fn set_next_event(&self, ns: u64);
+
+    /// Stop raising interrupts.
+    fn shutdown(&self);
+}
+
+/// The PIT, channel 0.
+struct Pit;
+
+impl ClockEvent for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn vector(&self) -> u8 {
+        PIC1_OFFSET
+    }
+
+    fn is_periodic_capable(&self) -> bool {
+        true
+    }
+
+    fn set_periodic(&self, hz: u64) -> u64 {
+        pit::set_periodic(hz)
+    }
+
+    fn set_next_event(&self, ns: u64) {
+        pit::set_oneshot(ns);
+    }
+
+    fn shutdown(&self) {
+        pit::stop();
+    }
+}
+
+/// HPET comparator 0, routed to IRQ0 in place of the PIT.
+struct Hpet;
+
+impl Hpet {
+    fn comparator() -> hpet::Comparator<'static> {
+        hpet::get().unwrap().comparator(0)
+    }
+}
+
+impl ClockEvent for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        150
+    }
+
+    fn vector(&self) -> u8 {
+        PIC1_OFFSET
+    }
+
+    fn is_periodic_capable(&self) -> bool {
+        Self::comparator().is_periodic_capable()
+    }
+
+    fn set_periodic(&self, hz: u64) -> u64 {
+        hpet::get().unwrap().enable_legacy_routing();
+        Self::comparator().periodic(NS_PER_SEC / hz);
+        hz
+    }
+
+    fn set_next_event(&self, ns: u64) {
+        hpet::get().unwrap().enable_legacy_routing();
+        Self::comparator().one_shot(ns);
+    }
+
+    fn shutdown(&self) {
+        Self::comparator().stop();
+        hpet::get().unwrap().disable_legacy_routing();
+    }
+}
+
+/// The Local APIC timer, counting down at a calibrated frequency.
+struct LapicTimer {
+    /// The frequency of the timer clock in Hz.
+    frequency: u64,
+}
+
+impl LapicTimer {
+    fn count(&self, ns: u64) -> u32 {
+        (ns as u128 * self.frequency as u128 / NS_PER_SEC as u128).clamp(1, u32::MAX as u128) as u32
+    }
+}
+
+impl ClockEvent for LapicTimer {
+    fn name(&self) -> &'static str {
+        "lapic"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn vector(&self) -> u8 {
+        lapic::TIMER_VECTOR
+    }
+
+    fn is_periodic_capable(&self) -> bool {
+        true
+    }
+
+    fn set_periodic(&self, hz: u64) -> u64 {
+        let count = self.count(NS_PER_SEC / hz);
+        lapic::get().start_timer(TimerMode::Periodic, count);
+
+        self.frequency / count as u64
+    }
+
+    fn set_next_event(&self, ns: u64) {
+        lapic::get().start_timer(TimerMode::OneShot, self.count(ns));
+    }
+
+    fn shutdown(&self) {
+        lapic::get().stop_timer();
+    }
+}
+
+/// The Local APIC timer in TSC-deadline mode, which is programmed in absolute TSC time and so
+/// needs no calibration of its own.
+struct TscDeadline;
+
+impl ClockEvent for TscDeadline {
+    fn name(&self) -> &'static str {
+        "tsc-deadline"
+    }
+
+    fn rating(&self) -> u32 {
+        350
+    }
+
+    fn vector(&self) -> u8 {
+        lapic::TIMER_VECTOR
+    }
+
+    fn is_periodic_capable(&self) -> bool {
+        false
+    }
+
+    fn set_periodic(&self, _: u64) -> u64 {
+        unreachable!("TSC-deadline mode is one-shot only")
+    }
+
+    fn set_next_event(&self, ns: u64) {
+        let tsc_hz = super::tsc_frequency().unwrap();
+        let delta = (ns as u128 * tsc_hz as u128 / NS_PER_SEC as u128) as u64;
+
+        let lapic = lapic::get();
+        lapic.start_timer(TimerMode::TscDeadline, 0);
+        lapic.set_tsc_deadline(super::rdtsc() + delta);
+    }
+
+    fn shutdown(&self) {
+        let lapic = lapic::get();
+        lapic.set_tsc_deadline(0);
+        lapic.stop_timer();
+    }
+}
+
+static LAPIC_TIMER: Once<LapicTimer> = Once::new();
+
+static DEVICES: IrqSpinLock<Vec<&'static dyn ClockEvent>> = IrqSpinLock::new(Vec::new());
+
+/// The device raising the tick, and whether it does so periodically or must be reprogrammed
+/// after every event.
+struct Current {
+    device: &'static dyn ClockEvent,
+    periodic: bool,
+}
+
+static CURRENT: IrqSpinLock<Option<Current>> = IrqSpinLock::new(None);
+
+/// Handle a timer interrupt, accounting for a tick if it was raised by the device in use.
+fn interrupt(frame: &mut TrapFrame) -> IrqReturn {
+    watchdog::tick(frame);
+
+    // The other CPUs only tick to show the watchdog they are alive.
+    if percpu::current_cpu() != 0 {
+        return IrqReturn::Handled;
+    }
+
+    let vector = frame.vector as u8;
+    let from_current = match CURRENT.lock().as_ref() {
+        Some(current) if current.device.vector() == vector => {
+            if !current.periodic {
+                current.device.set_next_event(tick_ns());
+            }
+
+            true
+        }
+        _ => false,
+    };
+
+    if from_current {
+        super::tick();
+    }
+
+    // Stray interrupts from a device that was switched away from are expected.
+    IrqReturn::Handled
+}
+
+/// Return the name of the device in use.
+pub fn current() -> Option<&'static str> {
+    CURRENT.lock().as_ref().map(|c| c.device.name())
+}
+
+/// Make `device` available for selection.
+pub fn register(device: &'static dyn ClockEvent) {
+    DEVICES.lock().push(device);
+
+    log::info!(
+        "clockevent: registered {} (rating {})",
+        device.name(),
+        device.rating()
+    );
+}
+
+/// Switch the tick to the highest rated device, running it periodically if possible.
+pub fn select() {
+    let Some(best) = DEVICES.lock().iter().copied().max_by_key(|d| d.rating()) else {
+        return;
+    };
+
+    let mut current = CURRENT.lock();
+
+    if let Some(old) = current.take() {
+        old.device.shutdown();
+    }
+
+    let periodic = best.is_periodic_capable();
+
+    if periodic {
+        let hz = best.set_periodic(HZ);
+        super::set_tick_ns(NS_PER_SEC / hz);
+    } else {
+        super::set_tick_ns(NS_PER_SEC / HZ);
+        best.set_next_event(tick_ns());
+    }
+
+    *current = Some(Current {
+        device: best,
+        periodic,
+    });
+
+    log::info!(
+        "clockevent: ticking at {} Hz from {} ({})",
+        NS_PER_SEC / tick_ns(),
+        best.name(),
+        if periodic { "periodic" } else { "one-shot" }
+    );
+}
+
+/// Start the Local APIC timer of an application processor, whose tick feeds the watchdog. Time
+/// is only kept on the BSP.
+pub fn init_ap() {
+    if let Some(timer) = LAPIC_TIMER.get() {
+        timer.set_periodic(HZ);
+    }
+}
+
+/// Register the available clock event devices and select the best one.
+pub(super) fn init() {
+    irq::register(irq::line(PIC1_OFFSET), interrupt, "timer")
+        .expect("clockevent: unable to register IRQ handler");
+
+    register(&Pit);
+
+    if hpet::get().is_some_and(|hpet| hpet.is_legacy_route_capable()) {
+        register(&Hpet);
+    }
+
+    if apic::is_enabled() {
+        let frequency = lapic::get().calibrate_timer(LAPIC_CALIBRATION_NS, super::ndelay);
+        register(LAPIC_TIMER.call_once(|| LapicTimer { frequency }));
+
+        irq::register(irq::line(lapic::TIMER_VECTOR), interrupt, "lapic-timer")
+            .expect("clockevent: unable to register local APIC timer handler");
+
+        if cpu::has(Feature::TscDeadline) && super::tsc_frequency().is_some() {
+            register(&TscDeadline);
+        }
+    }
+
+    select();
+}
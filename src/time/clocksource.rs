@@ -0,0 +1,241 @@
+//! Clock sources: free-running counters the monotonic clock is read from.
+
+use super::NS_PER_SEC;
+use crate::{
+    drivers::hpet,
+    sync::{IrqRwLock, IrqSpinLock},
+};
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+
+/// A free-running counter.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How suitable this source is; the highest rated stable source is used.
+    fn rating(&self) -> u32;
+
+    /// The frequency of the counter in Hz.
+    fn frequency(&self) -> u64;
+
+    fn read(&self) -> u64;
+}
+
+/// The invariant TSC, once calibrated.
+struct Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn frequency(&self) -> u64 {
+        super::tsc_frequency().expect("TSC registered before calibration")
+    }
+
+    fn read(&self) -> u64 {
+        super::rdtsc()
+    }
+}
+
+/// The main counter of the HPET.
+struct Hpet;
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn frequency(&self) -> u64 {
+        hpet::get().unwrap().frequency()
+    }
+
+    fn read(&self) -> u64 {
+        hpet::get().unwrap().counter()
+    }
+}
+
+/// The tick counter, which is always available but only as precise as the tick.
+struct Jiffies;
+
+impl ClockSource for Jiffies {
+    fn name(&self) -> &'static str {
+        "jiffies"
+    }
+
+    fn rating(&self) -> u32 {
+        1
+    }
+
+    fn frequency(&self) -> u64 {
+        NS_PER_SEC / super::tick_ns()
+    }
+
+    fn read(&self) -> u64 {
+        super::ticks()
+    }
+}
+
+struct Registered {
+    source: &'static dyn ClockSource,
+
+    /// Set when the source disagreed with the watchdog, which excludes it from selection.
+    unstable: bool,
+}
+
+static SOURCES: IrqSpinLock<Vec<Registered>> = IrqSpinLock::new(Vec::new());
+
+/// The source in use, along with the counter value and time it was switched to at, which keeps
+/// the monotonic clock continuous across switches.
+struct Current {
+    source: &'static dyn ClockSource,
+    base_count: u64,
+    base_ns: u64,
+}
+
+impl Current {
+    fn read_ns(&self) -> u64 {
+        let elapsed = self.source.read().wrapping_sub(self.base_count);
+        self.base_ns
+            + (elapsed as u128 * NS_PER_SEC as u128 / self.source.frequency() as u128) as u64
+    }
+}
+
+static CURRENT: IrqRwLock<Option<Current>> = IrqRwLock::new(None);
+
+/// Compares the current source against another one to detect it drifting.
+struct Watchdog {
+    reference: &'static dyn ClockSource,
+    source_ns: u64,
+    reference_count: u64,
+}
+
+static WATCHDOG: IrqSpinLock<Option<Watchdog>> = IrqSpinLock::new(None);
+
+/// Read the current source in nanoseconds, or `None` if no source has been selected.
+pub(super) fn read_ns() -> Option<u64> {
+    CURRENT.read().as_ref().map(Current::read_ns)
+}
+
+/// Return the name of the source in use.
+pub fn current() -> Option<&'static str> {
+    CURRENT.read().as_ref().map(|c| c.source.name())
+}
+
+/// Check if the source in use is finer grained than the tick.
+pub fn is_high_resolution() -> bool {
+    CURRENT
+        .read()
+        .as_ref()
+        .is_some_and(|c| c.source.frequency() > NS_PER_SEC / super::tick_ns())
+}
+
+/// Make `source` available for selection.
+pub fn register(source: &'static dyn ClockSource) {
+    SOURCES.lock().push(Registered {
+        source,
+        unstable: false,
+    });
+
+    log::info!(
+        "clocksource: registered {} ({} Hz, rating {})",
+        source.name(),
+        source.frequency(),
+        source.rating()
+    );
+}
+
+/// Switch to the highest rated stable source, and pick the best other source as its watchdog.
+pub fn select() {
+    let sources = SOURCES.lock();
+    let stable = sources.iter().filter(|r| !r.unstable).map(|r| r.source);
+
+    let Some(best) = stable.clone().max_by_key(|s| s.rating()) else {
+        return;
+    };
+
+    let reference = stable
+        .filter(|s| s.name() != best.name())
+        .max_by_key(|s| s.rating());
+
+    let mut current = CURRENT.write();
+    let now = current.as_ref().map_or(0, Current::read_ns);
+
+    if current.as_ref().map(|c| c.source.name()) != Some(best.name()) {
+        log::info!("clocksource: switched to {}", best.name());
+    }
+
+    *current = Some(Current {
+        source: best,
+        base_count: best.read(),
+        base_ns: now,
+    });
+
+    *WATCHDOG.lock() = reference.map(|reference| Watchdog {
+        reference,
+        source_ns: now,
+        reference_count: reference.read(),
+    });
+}
+
+/// Stop using `name`, which was found to be unreliable, and switch to the next best source.
+pub fn mark_unstable(name: &str) {
+    for registered in SOURCES.lock().iter_mut() {
+        if registered.source.name() == name {
+            registered.unstable = true;
+        }
+    }
+
+    log::warn!("clocksource: {name} is unstable");
+    select();
+}
+
+/// Check the current source against the watchdog reference, marking it unstable if they drifted
+/// apart by more than 1/16 since the last check.
+///
+/// Called from the tick every [`super::WATCHDOG_INTERVAL_TICKS`] ticks.
+pub(super) fn watchdog() {
+    let drifted = interrupts::without_interrupts(|| {
+        let name = current()?;
+        let now = read_ns()?;
+        let mut watchdog = WATCHDOG.lock();
+        let watchdog = watchdog.as_mut()?;
+
+        let reference_count = watchdog.reference.read();
+        let reference_elapsed = (reference_count.wrapping_sub(watchdog.reference_count) as u128
+            * NS_PER_SEC as u128
+            / watchdog.reference.frequency() as u128) as u64;
+        let source_elapsed = now - watchdog.source_ns;
+
+        watchdog.source_ns = now;
+        watchdog.reference_count = reference_count;
+
+        (source_elapsed.abs_diff(reference_elapsed) > reference_elapsed / 16).then_some(name)
+    });
+
+    if let Some(name) = drifted {
+        mark_unstable(name);
+    }
+}
+
+/// Register the available clock sources and select the best one.
+pub(super) fn init() {
+    if super::tsc_frequency().is_some() {
+        register(&Tsc);
+    }
+
+    if hpet::get().is_some() {
+        register(&Hpet);
+    }
+
+    register(&Jiffies);
+    select();
+}
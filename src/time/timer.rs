@@ -0,0 +1,280 @@
+//! Kernel timers: callbacks run in softirq context once the tick count reaches a deadline.
+//!
+//! Armed timers are kept in a hierarchical timer wheel. Level 0 has a slot for each of the next
+//! [`SLOTS`] ticks, and every further level has slots [`SLOTS`] times as wide as the one below.
+//! When time reaches a slot of a higher level, its timers cascade down into finer slots, so a
+//! timer is only moved a handful of times however far away its deadline is.
+
+use crate::{
+    irq::softirq::{self, Softirq},
+    sync::IrqSpinLock,
+};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const LEVELS: usize = 5;
+
+/// The amount of ticks the wheel spans. Timers further away than this are queued at its end and
+/// cascaded again until their deadline is in reach.
+const SPAN: u64 = 1 << (LEVEL_BITS * LEVELS as u32);
+
+/// The slot of a timer that is not queued.
+const NOT_QUEUED: usize = usize::MAX;
+
+struct Inner {
+    callback: Box<dyn Fn() + Send + Sync>,
+
+    /// The tick the timer expires at.
+    expires: AtomicU64,
+
+    /// The index of the wheel slot the timer is queued in, counting the slots of every level, or
+    /// [`NOT_QUEUED`].
+    slot: AtomicUsize,
+}
+
+/// A callback to run once, at or shortly after a deadline.
+///
+/// Dropping a timer cancels it.
+pub struct Timer {
+    inner: Arc<Inner>,
+}
+
+impl Timer {
+    pub fn new(callback: impl Fn() + Send + Sync + 'static) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                callback: Box::new(callback),
+                expires: AtomicU64::new(0),
+                slot: AtomicUsize::new(NOT_QUEUED),
+            }),
+        }
+    }
+
+    /// Run the callback once [`super::ticks`] reaches `deadline`, replacing the deadline the
+    /// timer was armed with, if any. A deadline already passed expires on the next tick.
+    pub fn arm(&self, deadline: u64) {
+        WHEEL.lock().add(&self.inner, deadline);
+    }
+
+    /// Run the callback once at least `ns` nanoseconds have elapsed.
+    pub fn arm_in(&self, ns: u64) {
+        // The current tick is partially elapsed already.
+        self.arm(super::ticks() + ns.div_ceil(super::tick_ns()) + 1);
+    }
+
+    /// Stop the timer from expiring. Returns `false` if it was not armed, in which case its
+    /// callback may be running or about to.
+    pub fn cancel(&self) -> bool {
+        WHEEL.lock().remove(&self.inner)
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.inner.slot.load(Ordering::Relaxed) != NOT_QUEUED
+    }
+
+    /// Return the tick the timer was last armed to expire at.
+    pub fn deadline(&self) -> u64 {
+        self.inner.expires.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+struct Wheel {
+    /// The next tick to process.
+    current: u64,
+    slots: [[Vec<Arc<Inner>>; SLOTS]; LEVELS],
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        const SLOT: Vec<Arc<Inner>> = Vec::new();
+        const LEVEL: [Vec<Arc<Inner>>; SLOTS] = [SLOT; SLOTS];
+
+        Self {
+            current: 0,
+            slots: [LEVEL; LEVELS],
+        }
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut Vec<Arc<Inner>> {
+        &mut self.slots[index / SLOTS][index % SLOTS]
+    }
+
+    /// Queue `timer` in the slot its deadline falls into, relative to the current tick.
+    fn insert(&mut self, timer: Arc<Inner>) {
+        let expires = timer.expires.load(Ordering::Relaxed).max(self.current);
+        let delta = expires - self.current;
+
+        let (level, expires) =
+            match (0..LEVELS).find(|&level| delta >> (LEVEL_BITS * (level as u32 + 1)) == 0) {
+                Some(level) => (level, expires),
+                None => (LEVELS - 1, self.current + SPAN - 1),
+            };
+
+        let slot = (expires >> (LEVEL_BITS * level as u32)) as usize % SLOTS;
+        let index = level * SLOTS + slot;
+
+        timer.slot.store(index, Ordering::Relaxed);
+        self.slot_mut(index).push(timer);
+    }
+
+    fn add(&mut self, timer: &Arc<Inner>, deadline: u64) {
+        self.remove(timer);
+
+        timer.expires.store(deadline, Ordering::Relaxed);
+        self.insert(timer.clone());
+    }
+
+    fn remove(&mut self, timer: &Arc<Inner>) -> bool {
+        let index = timer.slot.swap(NOT_QUEUED, Ordering::Relaxed);
+        if index == NOT_QUEUED {
+            return false;
+        }
+
+        let slot = self.slot_mut(index);
+        let position = slot
+            .iter()
+            .position(|queued| Arc::ptr_eq(queued, timer))
+            .expect("timer missing from its wheel slot");
+
+        slot.remove(position);
+        true
+    }
+
+    /// Move the timers of `slot` on `level` down to the levels their deadlines now fall into.
+    fn cascade(&mut self, level: usize, slot: usize) {
+        for timer in mem::take(&mut self.slots[level][slot]) {
+            self.insert(timer);
+        }
+    }
+
+    /// Process every tick up to and including `now`, returning the timers that expired in the
+    /// order of their deadlines.
+    fn expire(&mut self, now: u64) -> Vec<Arc<Inner>> {
+        let mut expired = Vec::new();
+
+        while self.current <= now {
+            // Once the lower levels wrap around, the next slot of the level above is due.
+            for level in 1..LEVELS {
+                if self.current & ((1 << (LEVEL_BITS * level as u32)) - 1) != 0 {
+                    break;
+                }
+
+                let slot = (self.current >> (LEVEL_BITS * level as u32)) as usize % SLOTS;
+                self.cascade(level, slot);
+            }
+
+            let slot = self.current as usize % SLOTS;
+            for timer in mem::take(&mut self.slots[0][slot]) {
+                timer.slot.store(NOT_QUEUED, Ordering::Relaxed);
+                expired.push(timer);
+            }
+
+            self.current += 1;
+        }
+
+        expired
+    }
+}
+
+static WHEEL: IrqSpinLock<Wheel> = IrqSpinLock::new(Wheel::new());
+
+/// Run the callbacks of the timers that expired by the current tick.
+fn run() {
+    let now = super::ticks();
+    let expired = WHEEL.lock().expire(now);
+
+    // Callbacks are run without the lock held, so that they may arm timers again.
+    for timer in expired {
+        (timer.callback)();
+    }
+}
+
+/// Raise the timer softirq. Called on every tick.
+pub(super) fn tick() {
+    softirq::raise(Softirq::Timer);
+}
+
+pub(super) fn init() {
+    WHEEL.lock().current = super::ticks();
+
+    softirq::open(Softirq::Timer, run);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Advance `wheel` up to `until`, returning each expired timer's deadline along with the
+    /// tick it expired at.
+    fn advance(wheel: &mut Wheel, until: u64) -> Vec<(u64, u64)> {
+        let mut expired = Vec::new();
+
+        while wheel.current <= until {
+            let now = wheel.current;
+            for timer in wheel.expire(now) {
+                expired.push((timer.expires.load(Ordering::Relaxed), now));
+            }
+        }
+
+        expired
+    }
+
+    #[test]
+    fn ordering() {
+        let mut wheel = Wheel::new();
+        let mut deadlines = [300, 5, 70, 5000, 64, 4096, 1, 262_147, 63];
+
+        let timers: Vec<Timer> = deadlines
+            .iter()
+            .map(|&deadline| {
+                let timer = Timer::new(|| {});
+                wheel.add(&timer.inner, deadline);
+                timer
+            })
+            .collect();
+
+        let expired = advance(&mut wheel, 300_000);
+        deadlines.sort_unstable();
+
+        assert_eq!(expired.len(), deadlines.len());
+        for (&deadline, &(expires, now)) in deadlines.iter().zip(&expired) {
+            assert_eq!(expires, deadline);
+            assert_eq!(now, deadline);
+        }
+
+        assert!(timers.iter().all(|timer| !timer.is_armed()));
+    }
+
+    #[test]
+    fn cancellation() {
+        let mut wheel = Wheel::new();
+        let first = Timer::new(|| {});
+        let second = Timer::new(|| {});
+        let third = Timer::new(|| {});
+
+        wheel.add(&first.inner, 10);
+        wheel.add(&second.inner, 10);
+        wheel.add(&third.inner, 1000);
+
+        assert!(wheel.remove(&first.inner));
+        assert!(!wheel.remove(&first.inner));
+        assert!(!first.is_armed());
+
+        // Arming again replaces the deadline.
+        wheel.add(&third.inner, 20);
+
+        assert_eq!(advance(&mut wheel, 2000), [(10, 10), (20, 20)]);
+        assert!(!wheel.remove(&second.inner));
+    }
+}
@@ -0,0 +1,362 @@
+//! Lockup detection.
+//!
+//! Every CPU counts its timer ticks, and a periodic NMI checks that the count keeps moving. A
+//! CPU that stops ticking for [`HARD_LOCKUP_SECS`], such as one spinning with interrupts
+//! disabled, is reported with its registers and backtrace, as is a CPU that ticks for
+//! [`SOFT_LOCKUP_SECS`] without returning to the idle loop.
+//!
+//! The NMI is raised by a performance counter counting unhalted cycles, so it stays quiet while
+//! a CPU idles in `hlt`. Without a usable counter every CPU sends the NMI to the next online one
+//! from its own tick instead, so that each is watched by another and a stuck BSP is reported by
+//! the last CPU. A single CPU without a counter goes unwatched.
+
+use crate::{
+    apic::{self, lapic},
+    backtrace,
+    cpu::{self, Vendor},
+    idt::TrapFrame,
+    logger,
+    percpu::{self, PerCpu},
+    smp,
+    time::{self, HZ},
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x86_64::registers::{model_specific::Msr, rflags::RFlags};
+
+/// How long a CPU may go without a tick before it is reported.
+const HARD_LOCKUP_SECS: u64 = 10;
+
+/// How long a CPU may go without returning to the idle loop before it is reported.
+const SOFT_LOCKUP_SECS: u64 = 20;
+
+/// How often the watchdog NMI is raised, in unhalted cycles or in time.
+const NMI_PERIOD_MS: u64 = 250;
+
+/// The amount of ticks between the watchdog NMIs sent to the next CPU.
+const NMI_PERIOD_TICKS: u64 = if NMI_PERIOD_MS * HZ / 1000 == 0 {
+    1
+} else {
+    NMI_PERIOD_MS * HZ / 1000
+};
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const PERFEVTSEL_UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const PERFEVTSEL_USR: u64 = 1 << 16;
+const PERFEVTSEL_OS: u64 = 1 << 17;
+const PERFEVTSEL_INT: u64 = 1 << 20;
+const PERFEVTSEL_EN: u64 = 1 << 22;
+
+/// The state the watchdog keeps for each CPU.
+struct Heartbeat {
+    /// The amount of timer ticks received.
+    ticks: AtomicU64,
+
+    /// The tick count seen by the last watchdog NMI.
+    seen: AtomicU64,
+
+    /// The amount of watchdog NMIs received since the tick count last moved.
+    missed: AtomicU64,
+
+    /// The amount of ticks received since the CPU last passed through the idle loop.
+    busy_ticks: AtomicU64,
+
+    hard_reported: AtomicBool,
+    soft_reported: AtomicBool,
+
+    /// Set once the performance counter of the CPU raises the watchdog NMI.
+    counting: AtomicBool,
+
+    /// Set before the previous CPU sends the CPU a watchdog NMI.
+    nmi_expected: AtomicBool,
+
+    apic_id: AtomicU32,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Self {
+            ticks: AtomicU64::new(0),
+            seen: AtomicU64::new(0),
+            missed: AtomicU64::new(0),
+            busy_ticks: AtomicU64::new(0),
+            hard_reported: AtomicBool::new(false),
+            soft_reported: AtomicBool::new(false),
+            counting: AtomicBool::new(false),
+            nmi_expected: AtomicBool::new(false),
+            apic_id: AtomicU32::new(0),
+        }
+    }
+}
+
+static HEARTBEATS: PerCpu<Heartbeat> = PerCpu::new(Heartbeat::new);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set when the CPUs send the watchdog NMI to each other, as there is no performance counter to
+/// raise it.
+static NMI_FROM_TICK: AtomicBool = AtomicBool::new(false);
+
+/// The amount of hard lockups reported.
+static HARD_LOCKUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Return the amount of core cycles between watchdog NMIs, if they can be counted.
+fn counter_period() -> Option<u64> {
+    let info = cpu::info();
+    let perfmon = info.perfmon.filter(|perfmon| perfmon.core_cycles)?;
+
+    if info.vendor != Vendor::Intel || perfmon.counters == 0 {
+        return None;
+    }
+
+    // Counters are written through their low 32 bits, which are sign-extended, so the period
+    // must fit in 31 bits. The TSC ticks at about the rate of the core clock.
+    let period = time::tsc_frequency()? * NMI_PERIOD_MS / 1000;
+    Some(period.min(i32::MAX as u64))
+}
+
+/// Load the first performance counter to overflow `period` cycles from now.
+fn reload_counter(period: u64) {
+    // SAFETY: the CPU has an architectural PMU with at least one counter.
+    unsafe { Msr::new(IA32_PMC0).write(period.wrapping_neg() & 0xFFFF_FFFF) }
+}
+
+/// Start counting unhalted cycles on the current CPU, raising an NMI every `period`.
+fn start_counter(period: u64) {
+    let perfmon = cpu::info().perfmon.unwrap();
+
+    reload_counter(period);
+    lapic::get().set_perf_nmi();
+
+    // SAFETY: as above. The global control register exists from version 2 on.
+    unsafe {
+        if perfmon.version >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_CTRL).write(1);
+        }
+
+        Msr::new(IA32_PERFEVTSEL0).write(
+            PERFEVTSEL_UNHALTED_CORE_CYCLES
+                | PERFEVTSEL_USR
+                | PERFEVTSEL_OS
+                | PERFEVTSEL_INT
+                | PERFEVTSEL_EN,
+        );
+    }
+}
+
+/// Check if the first performance counter overflowed, and rearm it if it did.
+fn counter_overflowed(period: u64) -> bool {
+    let perfmon = cpu::info().perfmon.unwrap();
+
+    // SAFETY: as above.
+    let value = unsafe { Msr::new(IA32_PMC0).read() };
+
+    // The counter counts up from the negated period, so its top bit is clear once it wraps.
+    if value & (1 << (perfmon.counter_width - 1)) != 0 {
+        return false;
+    }
+
+    reload_counter(period);
+
+    // SAFETY: as above.
+    unsafe {
+        if perfmon.version >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        }
+    }
+
+    lapic::get().set_perf_nmi();
+    true
+}
+
+/// Send the watchdog NMI to the next online CPU after the current one, which it watches.
+fn send_nmi_to_next() {
+    let current = percpu::current_cpu();
+    let count = smp::cpu_count();
+
+    let Some(next) = (1..count)
+        .map(|offset| (current + offset) % count)
+        .find(|&cpu| smp::is_online(cpu))
+    else {
+        return;
+    };
+
+    let heartbeat = HEARTBEATS.get_for(next);
+
+    heartbeat.nmi_expected.store(true, Ordering::Relaxed);
+    lapic::get().send_nmi(heartbeat.apic_id.load(Ordering::Relaxed));
+}
+
+/// Report a lockup of the current CPU. This runs in NMI context when reporting a hard lockup,
+/// possibly while the CPU or a stuck one holds the console locks.
+fn report(frame: &TrapFrame, kind: &str, secs: u64) {
+    logger::bust_spinlocks(true);
+
+    log::error!(
+        "watchdog: {kind} lockup, CPU {} stuck for {secs}s with interrupts {}",
+        percpu::current_cpu(),
+        if frame.rflags & RFlags::INTERRUPT_FLAG.bits() != 0 {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+
+    frame.dump();
+    backtrace::print_from(frame.rip, frame.rbp);
+
+    logger::bust_spinlocks(false);
+}
+
+/// Account for a timer tick on the current CPU, which interrupted `frame`.
+pub fn tick(frame: &TrapFrame) {
+    let heartbeat = HEARTBEATS.get();
+
+    let ticks = heartbeat.ticks.fetch_add(1, Ordering::Relaxed) + 1;
+    let busy_ticks = heartbeat.busy_ticks.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if NMI_FROM_TICK.load(Ordering::Relaxed) && ticks % NMI_PERIOD_TICKS == 0 {
+        send_nmi_to_next();
+    }
+
+    if busy_ticks < SOFT_LOCKUP_SECS * HZ {
+        return;
+    }
+
+    if !heartbeat.soft_reported.swap(true, Ordering::Relaxed) {
+        report(frame, "soft", busy_ticks / HZ);
+    }
+}
+
+/// Record that the current CPU passed through the idle loop.
+pub fn touch_idle() {
+    let heartbeat = HEARTBEATS.get();
+
+    heartbeat.busy_ticks.store(0, Ordering::Relaxed);
+    heartbeat.soft_reported.store(false, Ordering::Relaxed);
+}
+
+/// Handle an NMI that interrupted `frame`. Returns `false` if it was not raised by the
+/// watchdog.
+pub(crate) fn nmi(frame: &TrapFrame) -> bool {
+    let heartbeat = HEARTBEATS.get();
+    let from_watchdog = match counter_period() {
+        Some(period) if heartbeat.counting.load(Ordering::Relaxed) => counter_overflowed(period),
+        _ => heartbeat.nmi_expected.swap(false, Ordering::Relaxed),
+    };
+
+    if !from_watchdog {
+        return false;
+    }
+
+    // The counters of the application processors start before the BSP is done booting.
+    if !ENABLED.load(Ordering::Relaxed) {
+        return true;
+    }
+
+    let ticks = heartbeat.ticks.load(Ordering::Relaxed);
+    if heartbeat.seen.swap(ticks, Ordering::Relaxed) != ticks {
+        heartbeat.missed.store(0, Ordering::Relaxed);
+        heartbeat.hard_reported.store(false, Ordering::Relaxed);
+        return true;
+    }
+
+    let missed = heartbeat.missed.fetch_add(1, Ordering::Relaxed) + 1;
+    let secs = missed * NMI_PERIOD_MS / 1000;
+
+    if secs >= HARD_LOCKUP_SECS && !heartbeat.hard_reported.swap(true, Ordering::Relaxed) {
+        HARD_LOCKUPS.fetch_add(1, Ordering::Relaxed);
+        report(frame, "hard", secs);
+    }
+
+    true
+}
+
+/// Return the amount of hard lockups reported.
+pub fn hard_lockups() -> u64 {
+    HARD_LOCKUPS.load(Ordering::Relaxed)
+}
+
+/// Check if the watchdog watches the CPU numbered `cpu`.
+pub fn is_watched(cpu: usize) -> bool {
+    ENABLED.load(Ordering::Relaxed)
+        && (HEARTBEATS.get_for(cpu).counting.load(Ordering::Relaxed)
+            || NMI_FROM_TICK.load(Ordering::Relaxed) && smp::online_count() > 1)
+}
+
+/// Start watching an application processor.
+pub fn init_ap() {
+    if !apic::is_enabled() {
+        return;
+    }
+
+    let heartbeat = HEARTBEATS.get();
+    heartbeat
+        .apic_id
+        .store(lapic::get().id(), Ordering::Relaxed);
+
+    if let Some(period) = counter_period() {
+        start_counter(period);
+        heartbeat.counting.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Start watching every CPU, once the application processors are online.
+pub fn init() {
+    if !apic::is_enabled() {
+        log::warn!("watchdog: no local APIC, lockups will not be detected");
+        return;
+    }
+
+    init_ap();
+    touch_idle();
+    ENABLED.store(true, Ordering::Relaxed);
+
+    if counter_period().is_some() {
+        log::info!("watchdog: raising NMIs from the performance counters");
+    } else if smp::online_count() > 1 {
+        NMI_FROM_TICK.store(true, Ordering::Relaxed);
+        log::info!("watchdog: no usable performance counter, CPUs send NMIs to each other");
+    } else {
+        log::warn!(
+            "watchdog: no usable performance counter nor other CPU, lockups will not be detected"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::instructions::interrupts;
+
+    #[test]
+    fn reports_hard_lockup() {
+        if !is_watched(percpu::current_cpu()) {
+            log::warn!("watchdog: the current CPU is not watched, skipping");
+            return;
+        }
+
+        let before = hard_lockups();
+
+        // Stall with interrupts disabled until the lockup is reported, or well after it should
+        // have been.
+        interrupts::without_interrupts(|| {
+            for _ in 0..(HARD_LOCKUP_SECS + 5) * 1000 {
+                if hard_lockups() != before {
+                    break;
+                }
+
+                time::mdelay(1);
+            }
+        });
+
+        assert_eq!(hard_lockups(), before + 1);
+    }
+}
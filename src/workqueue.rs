@@ -0,0 +1,88 @@
+//! Workqueues: closures queued from any context except NMI and machine check, and run later from
+//! the idle loop, outside of interrupt context, where they may take as long as they need.
+//!
+//! Queueing allocates, and the heap lock can be held by the context an NMI or machine check
+//! interrupted, so those handlers must not queue work.
+
+use crate::sync::IrqSpinLock;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+
+type Work = Box<dyn FnOnce() + Send>;
+
+pub struct Workqueue {
+    name: &'static str,
+    queue: IrqSpinLock<VecDeque<Work>>,
+}
+
+impl Workqueue {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            queue: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Queue `work` to run after everything queued before it.
+    ///
+    /// This must not be called from NMI or machine check context, as it allocates.
+    pub fn queue(&self, work: impl FnOnce() + Send + 'static) {
+        let work: Work = Box::new(work);
+        self.queue.lock().push_back(work);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+
+    /// Run the queued work, including work queued while doing so. Returns the amount of items
+    /// run.
+    pub fn run(&self) -> usize {
+        let mut count = 0;
+
+        // The lock is released before running each item, so that it may queue more work.
+        while let Some(work) = self.queue.lock().pop_front() {
+            work();
+            count += 1;
+        }
+
+        count
+    }
+}
+
+/// The workqueue for work that does not need a queue of its own.
+pub static SYSTEM: Workqueue = Workqueue::new("events");
+
+/// Workqueues other than [`SYSTEM`] that the idle loop drains.
+static QUEUES: IrqSpinLock<Vec<&'static Workqueue>> = IrqSpinLock::new(Vec::new());
+
+/// Queue `work` on the system workqueue. Like [`Workqueue::queue`], this must not be called
+/// from NMI or machine check context.
+pub fn queue(work: impl FnOnce() + Send + 'static) {
+    SYSTEM.queue(work);
+}
+
+/// Have the idle loop drain `queue`.
+pub fn register(queue: &'static Workqueue) {
+    QUEUES.lock().push(queue);
+
+    log::info!("workqueue: registered {}", queue.name());
+}
+
+/// Check if any workqueue has work queued.
+pub fn has_pending() -> bool {
+    !SYSTEM.is_empty() || QUEUES.lock().iter().any(|queue| !queue.is_empty())
+}
+
+/// Run the work queued on every workqueue.
+pub fn run_pending() {
+    SYSTEM.run();
+
+    let queues = QUEUES.lock().clone();
+    for queue in queues {
+        queue.run();
+    }
+}